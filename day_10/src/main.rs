@@ -1,73 +1,591 @@
 extern crate im_rc;
 extern crate itertools;
 
+use im_rc::OrdMap;
 use im_rc::Vector;
 use itertools::Itertools;
-use std::collections::HashMap;
+use num_bigint::BigUint;
+use num_traits::One;
+use num_traits::Zero;
+use std::env;
+use std::fmt;
 
 fn main() {
     println!("--- [AoC 2020] Day 10: Adapter Array ---");
     let input = utils::read_integers_from_param();
-    part_one(&input);
-    part_two(&input);
+    let args: Vector<String> = env::args().collect();
+    let param = |name: &str| args.iter().skip_while(|arg| *arg != name).nth(1);
+    let defaults = Config::default();
+    let setting = |name: &str, default: i64| {
+        param(name).map_or(default, |value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} takes a number of jolts", name))
+        })
+    };
+    let config = Config {
+        min_step: setting("--min-step", defaults.min_step),
+        max_step: setting("--max-step", defaults.max_step),
+        outlet: setting("--outlet", defaults.outlet),
+        device_offset: setting("--device-offset", defaults.device_offset),
+    };
+
+    part_one(&input, &config);
+    part_two(&input, &config);
+
+    let chain = match AdapterChain::new(&input, &config) {
+        Ok(chain) => chain,
+        Err(_) => return,
+    };
+    if args.contains(&"--histogram".to_owned()) {
+        match chain.delta_histogram() {
+            Ok(histogram) => histogram
+                .iter()
+                .for_each(|(delta, count)| println!("{} jolts: {}", delta, count)),
+            Err(error) => println!("{}", error),
+        }
+    }
+    if let Some(count) = param("--list") {
+        let count = count
+            .parse()
+            .expect("--list takes a number of arrangements");
+        chain
+            .arrangements()
+            .take(count)
+            .for_each(|arrangement| println!("{}", arrangement.iter().join(", ")));
+    }
+    if let Some(n) = param("--nth") {
+        let n = n.parse().expect("--nth takes a number");
+        match chain.nth_arrangement(&n) {
+            Some(arrangement) => println!("{}", arrangement.iter().join(", ")),
+            None => println!("There are only {} arrangements", chain.count()),
+        }
+    }
+    if let Some(count) = param("--sample") {
+        let count = count
+            .parse()
+            .expect("--sample takes a number of arrangements");
+        let mut random = utils::XorShift::new(0x2545_f491_4f6c_dd1d);
+        for _ in 0..count {
+            if let Some(arrangement) = chain.sample(&mut || random.next_u64()) {
+                println!("{}", arrangement.iter().join(", "));
+            }
+        }
+    }
 }
 
-fn part_one(input: &Vector<i64>) {
-    let mut sorted_input: Vector<_> = input.iter().collect();
-    sorted_input.sort();
-    sorted_input.push_front(&0);
-    let (delta_1, delta_3) = sorted_input
-        .iter()
-        .tuple_windows()
-        .map(|(&a, &b)| b - a)
-        .fold((0, 1), |(delta_1, delta_3), delta| match delta {
-            1 => (delta_1 + 1, delta_3),
-            3 => (delta_1, delta_3 + 1),
-            _ => panic!("Found unknown delta"),
-        });
-    let result = delta_1 * delta_3;
-    println!(
-        "Solution to part one: {} ({} * {})",
-        result, delta_1, delta_3
-    );
+fn part_one(input: &Vector<i64>, config: &Config) {
+    let histogram = AdapterChain::new(input, config).and_then(|chain| chain.delta_histogram());
+    match histogram {
+        Ok(histogram) => {
+            let delta_1 = histogram.get(&1).copied().unwrap_or(0);
+            let delta_3 = histogram.get(&3).copied().unwrap_or(0);
+            println!(
+                "Solution to part one: {} ({} * {})",
+                delta_1 * delta_3,
+                delta_1,
+                delta_3
+            );
+        }
+        Err(error) => println!("No solution to part one: {}", error),
+    }
 }
 
-fn part_two(input: &Vector<i64>) {
-    let mut sorted_input: Vector<_> = input.iter().collect();
-    sorted_input.sort();
-    sorted_input.push_front(&0); // Add charging outlet
+fn part_two(input: &Vector<i64>, config: &Config) {
+    match AdapterChain::new(input, config).and_then(|chain| chain.arrangement_count()) {
+        Ok(result) => println!("Solution to part two: {}", result),
+        Err(error) => println!("No solution to part two: {}", error),
+    }
+}
 
-    // ways is a cache that contains the number of paths (combinations) that lead to
-    // a given adapter.
-    let mut ways: HashMap<i64, usize> = HashMap::new();
-    ways.insert(0, 1); // There is exactly one path to start: the charging outlet.
+// Each adapter takes an input between `min_step` and `max_step` jolts below its rating. The
+// chain starts at the outlet and the device is rated `device_offset` above the largest adapter,
+// a step that has to fit the window as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Config {
+    min_step: i64,
+    max_step: i64,
+    outlet: i64,
+    device_offset: i64,
+}
 
-    for pos in 1..sorted_input.len() {
-        let element = sorted_input.get(pos).copied().unwrap();
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            min_step: 1,
+            max_step: 3,
+            outlet: 0,
+            device_offset: 3,
+        }
+    }
+}
 
-        // Calculate the elements via which you could reach `element`
-        let prior_elements = (0..3).filter_map(|offset| {
-            sorted_input
-                .get(pos - offset - 1)
-                .filter(|&prior_element| *prior_element >= &(element - 3))
-        });
+impl Config {
+    fn allows(&self, step: i64) -> bool {
+        (self.min_step..=self.max_step).contains(&step)
+    }
+}
 
-        // Get the number of ways you could reach each of the possible
-        // prior elements.  We've calculated each of them in previous
-        // iterations.
-        // The number of paths leading to `element` is the sum of the number of paths
-        // you can take to each of the individual prior elements.
-        let paths_via_element = prior_elements
-            .map(|prior_elem| ways.get(prior_elem).unwrap())
-            .sum();
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ChainError {
+    EmptyWindow { min_step: i64, max_step: i64 },
+    DeviceOffset { config: Config },
+    BelowOutlet { adapter: i64, outlet: i64 },
+    Duplicate { joltage: i64 },
+    Gap { from: i64, to: i64, config: Config },
+}
 
-        // Store the number of paths leading to `element`
-        ways.insert(*element, paths_via_element);
-        // Clean up the number of paths to elements that are out of scope (too far behind).
-        ways.retain(|&k, _| k > element - 3);
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChainError::EmptyWindow { min_step, max_step } => write!(
+                f,
+                "Adapters can't step up at least {} and at most {} jolts",
+                min_step, max_step
+            ),
+            ChainError::DeviceOffset { config } => write!(
+                f,
+                "The device is rated {} jolts above the largest adapter, adapters step up {} to {} jolts",
+                config.device_offset, config.min_step, config.max_step
+            ),
+            ChainError::BelowOutlet { adapter, outlet } => write!(
+                f,
+                "Adapter {} is rated below the {} jolt outlet",
+                adapter, outlet
+            ),
+            ChainError::Duplicate { joltage } => {
+                write!(f, "There is more than one adapter rated {} jolts", joltage)
+            }
+            ChainError::Gap { from, to, config } => write!(
+                f,
+                "The gap from {} to {} jolts is {}, adapters step up {} to {} jolts",
+                from,
+                to,
+                to - from,
+                config.min_step,
+                config.max_step
+            ),
+        }
     }
+}
 
-    let result = ways.get(sorted_input.last().unwrap()).unwrap();
+// The adapters sorted by joltage, with the outlet at index 0. An arrangement is the list of
+// adapters used on the way from the outlet to the largest adapter, which the device needs; each
+// step up has to fit the configured window.
+struct AdapterChain {
+    config: Config,
+    joltages: Vector<i64>,
+    // The number of arrangements that continue from each position on to the largest adapter.
+    ways_from: Vec<BigUint>,
+}
+
+impl AdapterChain {
+    fn new(adapters: &Vector<i64>, config: &Config) -> Result<AdapterChain, ChainError> {
+        if config.min_step < 0 || config.min_step > config.max_step {
+            return Err(ChainError::EmptyWindow {
+                min_step: config.min_step,
+                max_step: config.max_step,
+            });
+        }
+        if !config.allows(config.device_offset) {
+            return Err(ChainError::DeviceOffset { config: *config });
+        }
+        let mut joltages = adapters.clone();
+        joltages.sort();
+        if let Some(&adapter) = joltages.head().filter(|&&lowest| lowest < config.outlet) {
+            return Err(ChainError::BelowOutlet {
+                adapter,
+                outlet: config.outlet,
+            });
+        }
+        // Arrangements are told apart by their joltages, so two adapters with the same rating
+        // would make every arrangement through them show up twice.
+        if let Some((&joltage, _)) = joltages.iter().tuple_windows().find(|(a, b)| a == b) {
+            return Err(ChainError::Duplicate { joltage });
+        }
+        joltages.push_front(config.outlet);
+
+        let mut ways_from = vec![BigUint::zero(); joltages.len()];
+        ways_from[joltages.len() - 1] = BigUint::one();
+        for position in (0..joltages.len() - 1).rev() {
+            let ways = AdapterChain::steps_from(&joltages, config, position)
+                .map(|next| &ways_from[next])
+                .sum();
+            ways_from[position] = ways;
+        }
+
+        Ok(AdapterChain {
+            config: *config,
+            joltages,
+            ways_from,
+        })
+    }
+
+    // The positions that can follow `position`, in increasing order of joltage.
+    fn steps_from<'a>(
+        joltages: &'a Vector<i64>,
+        config: &'a Config,
+        position: usize,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let joltage = joltages[position];
+        (position + 1..joltages.len())
+            .take_while(move |&next| joltages[next] <= joltage + config.max_step)
+            .filter(move |&next| joltages[next] >= joltage + config.min_step)
+    }
+
+    fn count(&self) -> BigUint {
+        self.ways_from[0].clone()
+    }
+
+    // Like `count`, but when there is no arrangement at all the error names where the chain
+    // breaks: the step from the highest joltage that can be reached to the next adapter up, which
+    // is either too small or too large to take.
+    fn arrangement_count(&self) -> Result<BigUint, ChainError> {
+        let count = self.count();
+        if !count.is_zero() {
+            return Ok(count);
+        }
+        let mut reachable = vec![false; self.joltages.len()];
+        reachable[0] = true;
+        for position in 0..self.joltages.len() {
+            if reachable[position] {
+                AdapterChain::steps_from(&self.joltages, &self.config, position)
+                    .for_each(|next| reachable[next] = true);
+            }
+        }
+        let last = reachable.iter().rposition(|&reached| reached).unwrap();
+        Err(ChainError::Gap {
+            from: self.joltages[last],
+            to: self.joltages[last + 1],
+            config: self.config,
+        })
+    }
+
+    // How often every step up occurs when all adapters are chained, from the outlet to the device.
+    fn delta_histogram(&self) -> Result<OrdMap<i64, usize>, ChainError> {
+        let device = *self.joltages.last().unwrap() + self.config.device_offset;
+        let mut histogram = OrdMap::new();
+        for (&from, &to) in self.joltages.iter().tuple_windows() {
+            if !self.config.allows(to - from) {
+                return Err(ChainError::Gap {
+                    from,
+                    to,
+                    config: self.config,
+                });
+            }
+            *histogram.entry(to - from).or_insert(0) += 1;
+        }
+        *histogram
+            .entry(device - self.joltages.last().unwrap())
+            .or_insert(0) += 1;
+        Ok(histogram)
+    }
+
+    fn arrangements(&self) -> Arrangements<'_> {
+        Arrangements {
+            chain: self,
+            path: Vec::new(),
+            started: false,
+        }
+    }
+
+    // Arrangements in lexicographic order of joltages, counting from 0. The counts say how many
+    // arrangements start with each choice, so the choices that come too early can be skipped whole.
+    fn nth_arrangement(&self, n: &BigUint) -> Option<Vector<i64>> {
+        if n >= &self.count() {
+            return None;
+        }
+        let mut remaining = n.clone();
+        let mut path = vec![0];
+        while *path.last().unwrap() != self.joltages.len() - 1 {
+            let position = *path.last().unwrap();
+            let next = AdapterChain::steps_from(&self.joltages, &self.config, position)
+                .find(|&next| {
+                    if remaining < self.ways_from[next] {
+                        true
+                    } else {
+                        remaining -= &self.ways_from[next];
+                        false
+                    }
+                })
+                .unwrap();
+            path.push(next);
+        }
+        Some(self.to_arrangement(&path))
+    }
 
-    println!("Solution to part two: {}", result);
+    // A uniformly chosen arrangement: `random` should produce uniformly distributed 64-bit words.
+    // Their low halves make up an arrangement number as wide as the count, which is drawn again when
+    // it reaches past the count; that happens less than half of the time.
+    fn sample(&self, random: &mut impl FnMut() -> u64) -> Option<Vector<i64>> {
+        let count = self.count();
+        if count.is_zero() {
+            return None;
+        }
+        let bits = count.bits();
+        let digits = bits.div_ceil(32);
+        loop {
+            let n = BigUint::new((0..digits).map(|_| random() as u32).collect())
+                >> (digits * 32 - bits);
+            if n < count {
+                return self.nth_arrangement(&n);
+            }
+        }
+    }
+
+    fn to_arrangement(&self, path: &[usize]) -> Vector<i64> {
+        path.iter()
+            .skip(1)
+            .map(|&position| self.joltages[position])
+            .collect()
+    }
+}
+
+// Walks the arrangements depth first, trying lower joltages first, which yields them in
+// lexicographic order. Dead ends are never entered because a position is only taken when
+// arrangements continue from it.
+struct Arrangements<'a> {
+    chain: &'a AdapterChain,
+    path: Vec<usize>,
+    started: bool,
+}
+
+impl Arrangements<'_> {
+    fn next_step(&self, position: usize, after: usize) -> Option<usize> {
+        AdapterChain::steps_from(&self.chain.joltages, &self.chain.config, position)
+            .filter(|&next| next > after)
+            .find(|&next| !self.chain.ways_from[next].is_zero())
+    }
+
+    // Follows the first choices from the end of the path on to the largest adapter.
+    fn complete(&mut self) {
+        while *self.path.last().unwrap() != self.chain.joltages.len() - 1 {
+            let position = *self.path.last().unwrap();
+            let next = self.next_step(position, position).unwrap();
+            self.path.push(next);
+        }
+    }
+}
+
+impl Iterator for Arrangements<'_> {
+    type Item = Vector<i64>;
+
+    fn next(&mut self) -> Option<Vector<i64>> {
+        if !self.started {
+            self.started = true;
+            if self.chain.count().is_zero() {
+                return None;
+            }
+            self.path.push(0);
+        } else {
+            loop {
+                let last = self.path.pop()?;
+                let position = *self.path.last()?;
+                if let Some(next) = self.next_step(position, last) {
+                    self.path.push(next);
+                    break;
+                }
+            }
+        }
+        self.complete();
+        Some(self.chain.to_arrangement(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use im_rc::{ordmap, vector};
+
+    #[test]
+    fn test_count() {
+        let small = utils::read_integers("input/small.txt").unwrap();
+        assert_eq!(
+            AdapterChain::new(&small, &Config::default())
+                .unwrap()
+                .count(),
+            BigUint::from(8u32)
+        );
+        let test = utils::read_integers("input/test.txt").unwrap();
+        assert_eq!(
+            AdapterChain::new(&test, &Config::default())
+                .unwrap()
+                .count(),
+            BigUint::from(19208u32)
+        );
+
+        // 300 adapters one jolt apart give tribonacci many arrangements, far beyond 64 bits.
+        let long: Vector<i64> = (1..=300).collect();
+        let count = AdapterChain::new(&long, &Config::default())
+            .unwrap()
+            .count();
+        assert!(count.bits() > 128);
+        let (expected, _, _) = (0..298).fold(
+            (BigUint::from(2u32), BigUint::one(), BigUint::one()),
+            |(a, b, c), _| (&a + &b + &c, a, b),
+        );
+        assert_eq!(count, expected);
+
+        assert!(AdapterChain::new(&vector!(1, 5), &Config::default())
+            .unwrap()
+            .count()
+            .is_zero());
+        assert_eq!(
+            AdapterChain::new(&vector!(1, 5), &Config::default())
+                .unwrap()
+                .arrangements()
+                .next(),
+            None
+        );
+        assert_eq!(
+            AdapterChain::new(&vector!(1, 1, 2), &Config::default()).err(),
+            Some(ChainError::Duplicate { joltage: 1 })
+        );
+    }
+
+    #[test]
+    fn test_arrangements() {
+        let small = utils::read_integers("input/small.txt").unwrap();
+        let chain = AdapterChain::new(&small, &Config::default()).unwrap();
+        let arrangements: Vec<Vector<i64>> = chain.arrangements().collect();
+        assert_eq!(arrangements.len(), 8);
+        assert_eq!(
+            arrangements[0],
+            vector!(1, 4, 5, 6, 7, 10, 11, 12, 15, 16, 19)
+        );
+        assert_eq!(arrangements[7], vector!(1, 4, 7, 10, 12, 15, 16, 19));
+        assert!(arrangements.windows(2).all(|pair| pair[0] < pair[1]));
+
+        for (n, arrangement) in arrangements.iter().enumerate() {
+            assert_eq!(
+                chain.nth_arrangement(&BigUint::from(n)).as_ref(),
+                Some(arrangement)
+            );
+        }
+        assert_eq!(chain.nth_arrangement(&BigUint::from(8u32)), None);
+
+        let test = utils::read_integers("input/test.txt").unwrap();
+        let chain = AdapterChain::new(&test, &Config::default()).unwrap();
+        let last = chain.arrangements().last().unwrap();
+        assert_eq!(
+            chain.nth_arrangement(&(chain.count() - BigUint::one())),
+            Some(last)
+        );
+
+        let mut generator = utils::XorShift::new(0x9e37_79b9_7f4a_7c15);
+        let mut random = move || generator.next_u64();
+        let long: Vector<i64> = (1..=300).collect();
+        let chain = AdapterChain::new(&long, &Config::default()).unwrap();
+        for _ in 0..10 {
+            let sample = chain.sample(&mut random).unwrap();
+            assert_eq!(sample.last(), Some(&300));
+            assert!(sample
+                .iter()
+                .tuple_windows()
+                .all(|(a, b)| (1..=3).contains(&(b - a))));
+        }
+    }
+
+    #[test]
+    fn test_configuration() {
+        let small = utils::read_integers("input/small.txt").unwrap();
+        let chain = AdapterChain::new(&small, &Config::default()).unwrap();
+        assert_eq!(chain.delta_histogram().unwrap(), ordmap! {1 => 7, 3 => 5});
+
+        let wide = Config {
+            max_step: 4,
+            ..Config::default()
+        };
+        let gaps = AdapterChain::new(&vector!(3, 7, 8), &wide).unwrap();
+        assert_eq!(
+            gaps.delta_histogram().unwrap(),
+            ordmap! {1 => 1, 3 => 2, 4 => 1}
+        );
+        assert_eq!(gaps.arrangement_count(), Ok(BigUint::one()));
+
+        let gap = AdapterChain::new(&vector!(3, 7, 8), &Config::default()).unwrap();
+        let error = ChainError::Gap {
+            from: 3,
+            to: 7,
+            config: Config::default(),
+        };
+        assert_eq!(gap.delta_histogram(), Err(error.clone()));
+        assert_eq!(gap.arrangement_count(), Err(error.clone()));
+        assert_eq!(
+            error.to_string(),
+            "The gap from 3 to 7 jolts is 4, adapters step up 1 to 3 jolts"
+        );
+
+        let too_close = Config {
+            min_step: 2,
+            ..Config::default()
+        };
+        assert_eq!(
+            AdapterChain::new(&small, &too_close)
+                .unwrap()
+                .arrangement_count(),
+            Err(ChainError::Gap {
+                from: 0,
+                to: 1,
+                config: too_close
+            })
+        );
+
+        let offset = Config {
+            outlet: 10,
+            device_offset: 3,
+            min_step: 2,
+            max_step: 3,
+        };
+        let chain = AdapterChain::new(&vector!(12, 14, 15, 17), &offset).unwrap();
+        assert_eq!(chain.arrangement_count(), Ok(BigUint::from(2u32)));
+        assert_eq!(
+            chain.arrangements().collect::<Vec<_>>(),
+            vec!(vector!(12, 14, 17), vector!(12, 15, 17))
+        );
+        assert_eq!(
+            chain.delta_histogram().err().map(|error| error.to_string()),
+            Some("The gap from 14 to 15 jolts is 1, adapters step up 2 to 3 jolts".to_owned())
+        );
+        assert_eq!(
+            AdapterChain::new(&vector!(13, 14, 17), &offset)
+                .unwrap()
+                .arrangement_count(),
+            Err(ChainError::Gap {
+                from: 13,
+                to: 14,
+                config: offset
+            })
+        );
+        assert_eq!(
+            AdapterChain::new(&vector!(5, 9), &offset).err(),
+            Some(ChainError::BelowOutlet {
+                adapter: 5,
+                outlet: 10
+            })
+        );
+        assert_eq!(
+            AdapterChain::new(
+                &small,
+                &Config {
+                    min_step: 4,
+                    ..offset
+                }
+            )
+            .err(),
+            Some(ChainError::EmptyWindow {
+                min_step: 4,
+                max_step: 3
+            })
+        );
+        let negative = Config {
+            device_offset: -1,
+            ..Config::default()
+        };
+        assert_eq!(
+            AdapterChain::new(&small, &negative).err(),
+            Some(ChainError::DeviceOffset { config: negative })
+        );
+    }
 }
@@ -1,43 +1,291 @@
 extern crate im_rc;
 
+use im_rc::vector;
+use im_rc::HashMap;
 use im_rc::Vector;
+use itertools::Itertools;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::convert::TryFrom;
-use std::convert::TryInto;
+use std::env;
 use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::mem;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn main() {
     println!("--- [AoC 2020] Day 11: Seating System ---");
     let input = utils::read_strings_from_param();
     part_one(&input);
     part_two(&input);
+
+    let args: Vector<String> = env::args().collect();
+    let param = |name: &str| args.iter().skip_while(|arg| *arg != name).nth(1);
+    let exports = ["--animate", "--frames", "--history"];
+    if ["--neighbourhood", "--occupy", "--vacate", "--generate"]
+        .iter()
+        .chain(exports.iter())
+        .any(|name| param(name).is_some())
+    {
+        let rules = Rules::from_params(
+            param("--neighbourhood").map_or("adjacent", |value| value.as_str()),
+            param("--occupy").map(|value| value.as_str()),
+            param("--vacate").map(|value| value.as_str()),
+        )
+        .unwrap_or_else(|error| panic!("{}", error));
+        let layout = match param("--generate") {
+            Some(size) => generate_layout(size.parse().expect("Size should be a number"), 2020),
+            None => Layout::try_from(&input).unwrap_or_else(|error| panic!("{}", error)),
+        };
+
+        let start = Instant::now();
+        let mut simulator = Simulator::new(&layout, &rules);
+        if exports.iter().any(|name| param(name).is_some()) {
+            let limit = param("--generations").map_or(1000, |limit| limit.parse().unwrap());
+            let delay = param("--animate").map(|delay| delay.parse().unwrap());
+            let scale = param("--scale").map_or(4, |scale| {
+                scale
+                    .parse()
+                    .ok()
+                    .filter(|&scale: &usize| scale > 0)
+                    .expect("Scale should be a positive number")
+            });
+            if let Some(directory) = param("--frames") {
+                fs::create_dir_all(directory).expect("Failed to create the frames directory");
+            }
+
+            let history = simulator.run(limit, |simulator, stats| {
+                if let Some(delay) = delay {
+                    println!("\x1b[2J\x1b[H{}{}", simulator.layout(), stats);
+                    thread::sleep(Duration::from_millis(delay));
+                }
+                if let Some(directory) = param("--frames") {
+                    let path =
+                        Path::new(directory).join(format!("frame_{:05}.ppm", stats.generation));
+                    fs::write(path, simulator.to_ppm(scale)).expect("Failed to write frame");
+                }
+            });
+            if let Some(path) = param("--history") {
+                fs::write(path, history_to_csv(&history)).expect("Failed to write history");
+            }
+            println!("Recorded {} generations", history.len());
+            return;
+        }
+        match simulator.settle() {
+            Ok(generations) => println!(
+                "Occupied seats with {}: {} after {} generations ({:?})",
+                rules,
+                simulator.nb_of_occupied_seats(),
+                generations,
+                start.elapsed()
+            ),
+            Err(oscillation) => println!("{}", oscillation),
+        }
+    }
 }
 
 fn part_one(input: &Vector<String>) {
     let layout = Layout::try_from(input).unwrap();
-
-    let states = itertools::iterate(Some(layout), |l| l.as_ref().and_then(|l| l.next()))
-        .take_while(|l| l.is_some())
-        .filter_map(|l| l);
-
-    let final_state = states.last().unwrap();
-    let result = final_state.nb_of_occupied_seats();
+    let result = layout
+        .settle(&Rules::adjacent())
+        .unwrap_or_else(|oscillation| panic!("{}", oscillation))
+        .nb_of_occupied_seats();
 
     println!("Solution to part one: {}", result);
 }
 
 fn part_two(input: &Vector<String>) {
     let layout = Layout::try_from(input).unwrap();
+    let result = layout
+        .settle(&Rules::line_of_sight())
+        .unwrap_or_else(|oscillation| panic!("{}", oscillation))
+        .nb_of_occupied_seats();
 
-    let states = itertools::iterate(Some(layout), |l| l.as_ref().and_then(|l| l.next_v2()))
-        .take_while(|l| l.is_some())
-        .filter_map(|l| l);
-
-    let final_state = states.last().unwrap();
-    let result = final_state.nb_of_occupied_seats();
     println!("Solution to part two: {}", result);
 }
 
-#[derive(Clone, PartialEq, Copy, Debug, Eq, PartialOrd, Ord)]
+const DIRECTIONS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+#[derive(Clone, PartialEq, Debug, Eq)]
+enum Neighbourhood {
+    // The eight surrounding positions.
+    Adjacent,
+    // The first seat in each of the eight directions, looking past floor up to `max_distance`.
+    LineOfSight { max_distance: Option<usize> },
+    // The positions at the given (dx, dy) offsets.
+    Offsets(Vector<(i32, i32)>),
+}
+
+impl fmt::Display for Neighbourhood {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Adjacent => write!(f, "adjacent"),
+            Self::LineOfSight { max_distance: None } => write!(f, "sight"),
+            Self::LineOfSight {
+                max_distance: Some(distance),
+            } => write!(f, "sight:{}", distance),
+            Self::Offsets(offsets) => write!(
+                f,
+                "offsets:{}",
+                offsets
+                    .iter()
+                    .map(|(dx, dy)| format!("{},{}", dx, dy))
+                    .join(";")
+            ),
+        }
+    }
+}
+
+// Accepts "adjacent", "sight", "sight:<max distance>" or "offsets:<dx>,<dy>;<dx>,<dy>;...".
+impl TryFrom<&str> for Neighbourhood {
+    type Error = RuleError;
+
+    fn try_from(input: &str) -> Result<Neighbourhood, Self::Error> {
+        let (name, argument) = match input.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (input, None),
+        };
+        match (name, argument) {
+            ("adjacent", None) => Ok(Self::Adjacent),
+            ("sight", None) => Ok(Self::LineOfSight { max_distance: None }),
+            ("sight", Some(distance)) => Ok(Self::LineOfSight {
+                max_distance: Some(parse_number(distance)?),
+            }),
+            ("offsets", Some(offsets)) => offsets
+                .split(';')
+                .map(|offset| {
+                    offset
+                        .split_once(',')
+                        .and_then(|(dx, dy)| {
+                            Some((dx.trim().parse().ok()?, dy.trim().parse().ok()?))
+                        })
+                        .filter(|&offset| offset != (0, 0))
+                        .ok_or_else(|| RuleError::InvalidOffset(offset.to_owned()))
+                })
+                .collect::<Result<_, _>>()
+                .map(Self::Offsets),
+            _ => Err(RuleError::UnknownNeighbourhood(input.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Eq)]
+enum RuleError {
+    UnknownNeighbourhood(String),
+    InvalidOffset(String),
+    InvalidNumber(String),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownNeighbourhood(input) => write!(
+                f,
+                "Unknown neighbourhood '{}', expected adjacent, sight, sight:N or offsets:dx,dy;...",
+                input
+            ),
+            Self::InvalidOffset(input) => {
+                write!(f, "Offset '{}' should be a non-zero dx,dy pair", input)
+            }
+            Self::InvalidNumber(input) => write!(f, "'{}' should be a seat count", input),
+        }
+    }
+}
+
+fn parse_number(input: &str) -> Result<usize, RuleError> {
+    input
+        .trim()
+        .parse()
+        .map_err(|_| RuleError::InvalidNumber(input.to_owned()))
+}
+
+// An empty seat becomes occupied when at most `occupy_at_most` of its neighbours are occupied, an
+// occupied seat is vacated when at least `vacate_at_least` of them are.
+#[derive(Clone, PartialEq, Debug, Eq)]
+struct Rules {
+    neighbourhood: Neighbourhood,
+    occupy_at_most: usize,
+    vacate_at_least: usize,
+}
+
+impl Rules {
+    fn adjacent() -> Rules {
+        Rules {
+            neighbourhood: Neighbourhood::Adjacent,
+            occupy_at_most: 0,
+            vacate_at_least: 4,
+        }
+    }
+
+    fn line_of_sight() -> Rules {
+        Rules {
+            neighbourhood: Neighbourhood::LineOfSight { max_distance: None },
+            occupy_at_most: 0,
+            vacate_at_least: 5,
+        }
+    }
+
+    // Thresholds that are not given default to those of the matching puzzle part.
+    fn from_params(
+        neighbourhood: &str,
+        occupy: Option<&str>,
+        vacate: Option<&str>,
+    ) -> Result<Rules, RuleError> {
+        let neighbourhood = Neighbourhood::try_from(neighbourhood)?;
+        let defaults = match neighbourhood {
+            Neighbourhood::LineOfSight { .. } => Rules::line_of_sight(),
+            _ => Rules::adjacent(),
+        };
+        Ok(Rules {
+            neighbourhood,
+            occupy_at_most: occupy.map_or(Ok(defaults.occupy_at_most), parse_number)?,
+            vacate_at_least: vacate.map_or(Ok(defaults.vacate_at_least), parse_number)?,
+        })
+    }
+
+    #[cfg(test)]
+    fn next_state(&self, position: &Position, occupied_neighbours: usize) -> Position {
+        match position {
+            Position::Seat(occupied) => {
+                Position::Seat(self.next_occupancy(*occupied, occupied_neighbours))
+            }
+            Position::Floor => Position::Floor,
+        }
+    }
+
+    fn next_occupancy(&self, occupied: bool, occupied_neighbours: usize) -> bool {
+        if occupied {
+            occupied_neighbours < self.vacate_at_least
+        } else {
+            occupied_neighbours <= self.occupy_at_most
+        }
+    }
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} neighbours, occupying at most {} and vacating at least {}",
+            self.neighbourhood, self.occupy_at_most, self.vacate_at_least
+        )
+    }
+}
+
+#[derive(Clone, PartialEq, Copy, Debug, Eq, PartialOrd, Ord, Hash)]
 enum Position {
     Seat(bool),
     Floor,
@@ -45,10 +293,7 @@ enum Position {
 
 impl Position {
     fn is_occupied(&self) -> bool {
-        match self {
-            Self::Seat(true) => true,
-            _ => false,
-        }
+        matches!(self, Self::Seat(true))
     }
 }
 
@@ -75,6 +320,22 @@ impl TryFrom<&char> for Position {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+struct Oscillation {
+    generation: usize,
+    period: usize,
+}
+
+impl fmt::Display for Oscillation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The seats never settle, generation {} repeats every {} generations",
+            self.generation, self.period
+        )
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 struct Layout {
     seats: Vector<Vector<Position>>,
@@ -96,104 +357,92 @@ impl Layout {
     fn nb_of_occupied_seats(&self) -> usize {
         self.seats
             .iter()
-            .map(|r| r.iter())
-            .flatten()
-            .filter(|seat| match seat {
-                Position::Seat(true) => true,
-                _ => false,
-            })
+            .flat_map(|r| r.iter())
+            .filter(|seat| seat.is_occupied())
             .count()
     }
 
-    fn get_surroundings(&self, x: usize, y: usize) -> Vector<&Position> {
-        let minus_one: i32 = -1;
-        let plus_one: i32 = 1;
-        let positions = (minus_one..=plus_one)
-            .flat_map(|dy| (minus_one..=plus_one).map(move |dx| (dx, dy)))
-            .filter(|(dx, dy)| !(dx == &0 && dy == &0))
-            .map(|(dx, dy)| (x as i32 + dx, y as i32 + dy));
-
-        positions
-            .filter_map(|(x, y)| {
-                if let Ok(usize_x) = usize::try_from(x) {
-                    if let Ok(usize_y) = usize::try_from(y) {
-                        Some((usize_x, usize_y))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .filter_map(|(x, y)| self.seats.get(y).and_then(|r| r.get(x)))
+    #[cfg(test)]
+    fn neighbours(&self, x: usize, y: usize, neighbourhood: &Neighbourhood) -> Vector<&Position> {
+        self.neighbour_coordinates(x, y, neighbourhood)
+            .iter()
+            .filter_map(|&(x, y)| self.get(x, y))
             .collect()
     }
 
-    fn get_directions(&self, x: usize, y: usize) -> Vector<&Position> {
-        let minus_one: i8 = -1;
-        let plus_one: i8 = 1;
-        let directions = (minus_one..=plus_one)
-            .flat_map(|dy| (minus_one..=plus_one).map(move |dx| (dx, dy)))
-            .filter(|(dx, dy)| !(dx == &0 && dy == &0));
+    fn neighbour_coordinates(
+        &self,
+        x: usize,
+        y: usize,
+        neighbourhood: &Neighbourhood,
+    ) -> Vector<(usize, usize)> {
+        match neighbourhood {
+            Neighbourhood::Adjacent => self.at_offsets(x, y, DIRECTIONS.iter()),
+            Neighbourhood::LineOfSight { max_distance } => DIRECTIONS
+                .iter()
+                .filter_map(|&(dx, dy)| self.first_seat(x, y, dx, dy, *max_distance))
+                .collect(),
+            Neighbourhood::Offsets(offsets) => self.at_offsets(x, y, offsets.iter()),
+        }
+    }
 
-        directions
-            .filter_map(|(dx, dy)| self.get_direction(x, y, dx, dy))
+    fn at_offsets<'a>(
+        &self,
+        x: usize,
+        y: usize,
+        offsets: impl Iterator<Item = &'a (i32, i32)>,
+    ) -> Vector<(usize, usize)> {
+        offsets
+            .filter_map(|&(dx, dy)| self.get_offset(x, y, dx, dy))
             .collect()
     }
 
-    fn get_direction(&self, x: usize, y: usize, dx: i8, dy: i8) -> Option<&Position> {
-        let qx: i32 = x as i32 + dx as i32;
-        let qy: i32 = y as i32 + dy as i32;
+    fn get_offset(&self, x: usize, y: usize, dx: i32, dy: i32) -> Option<(usize, usize)> {
+        let qx = usize::try_from(x as i64 + dx as i64).ok()?;
+        let qy = usize::try_from(y as i64 + dy as i64).ok()?;
+        self.get(qx, qy).map(|_| (qx, qy))
+    }
 
-        if qx >= 0 && qy >= 0 && qx < self.width() as i32 && qy < self.height() as i32 {
-            let safe_x: usize = qx.try_into().unwrap();
-            let safe_y: usize = qy.try_into().unwrap();
-            self.get(safe_x, safe_y)
-                .filter(|pos| match pos {
-                    Position::Floor => false,
-                    Position::Seat(_) => true,
-                })
-                .or_else(|| self.get_direction(safe_x, safe_y, dx, dy))
-        } else {
-            None
-        }
+    fn first_seat(
+        &self,
+        x: usize,
+        y: usize,
+        dx: i32,
+        dy: i32,
+        max_distance: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        (1..)
+            .take_while(|&distance| max_distance.is_none_or(|max| distance <= max))
+            .map(|distance| self.get_offset(x, y, dx * distance as i32, dy * distance as i32))
+            .take_while(|coordinates| coordinates.is_some())
+            .flatten()
+            .find(|&(x, y)| matches!(self.get(x, y), Some(Position::Seat(_))))
     }
 
-    fn next(&self) -> Option<Layout> {
+    // The straightforward step, kept as the reference the simulator is checked against.
+    #[cfg(test)]
+    fn step(&self, rules: &Rules) -> Option<Layout> {
         let changes: Vector<_> = self
             .seats
             .iter()
             .enumerate()
             .flat_map(|(y, row)| {
-                row.iter()
-                    .enumerate()
-                    .filter_map(move |(x, seat)| match seat {
-                        Position::Seat(true) => {
-                            let surroundings = self.get_surroundings(x, y);
-                            let needs_change = surroundings
-                                .iter()
-                                .filter(|position| position.is_occupied())
-                                .count()
-                                >= 4;
-
-                            if needs_change {
-                                Some((x, y, Position::Seat(false)))
-                            } else {
-                                None
-                            }
-                        }
-                        Position::Seat(false) => {
-                            let surroundings = self.get_surroundings(x, y);
-                            let needs_change =
-                                surroundings.iter().all(|position| !position.is_occupied());
-                            if needs_change {
-                                Some((x, y, Position::Seat(true)))
-                            } else {
-                                None
-                            }
-                        }
-                        _ => None,
-                    })
+                row.iter().enumerate().filter_map(move |(x, seat)| {
+                    if let Position::Floor = seat {
+                        return None;
+                    }
+                    let occupied = self
+                        .neighbours(x, y, &rules.neighbourhood)
+                        .iter()
+                        .filter(|position| position.is_occupied())
+                        .count();
+                    let next = rules.next_state(seat, occupied);
+                    if next != *seat {
+                        Some((x, y, next))
+                    } else {
+                        None
+                    }
+                })
             })
             .collect();
 
@@ -204,51 +453,13 @@ impl Layout {
         }
     }
 
-    fn next_v2(&self) -> Option<Layout> {
-        let changes: Vector<_> = self
-            .seats
-            .iter()
-            .enumerate()
-            .flat_map(|(y, row)| {
-                row.iter()
-                    .enumerate()
-                    .filter_map(move |(x, seat)| match seat {
-                        Position::Seat(true) => {
-                            let surroundings = self.get_directions(x, y);
-                            let needs_change = surroundings
-                                .iter()
-                                .filter(|position| position.is_occupied())
-                                .count()
-                                >= 5;
-
-                            if needs_change {
-                                Some((x, y, Position::Seat(false)))
-                            } else {
-                                None
-                            }
-                        }
-                        Position::Seat(false) => {
-                            let surroundings = self.get_directions(x, y);
-                            let needs_change =
-                                surroundings.iter().all(|position| !position.is_occupied());
-                            if needs_change {
-                                Some((x, y, Position::Seat(true)))
-                            } else {
-                                None
-                            }
-                        }
-                        _ => None,
-                    })
-            })
-            .collect();
-
-        if changes.is_empty() {
-            None
-        } else {
-            Some(self.apply_changes(&changes))
-        }
+    fn settle(&self, rules: &Rules) -> Result<Layout, Oscillation> {
+        let mut simulator = Simulator::new(self, rules);
+        simulator.settle()?;
+        Ok(simulator.layout())
     }
 
+    #[cfg(test)]
     fn apply_changes(&self, changes: &Vector<(usize, usize, Position)>) -> Layout {
         let new_seats: Vector<Vector<Position>> = self
             .seats
@@ -280,11 +491,290 @@ impl Layout {
     }
 }
 
+// Seats are numbered in row-major order and only they take part in the simulation. Every seat's
+// neighbours are resolved to seat numbers once and stored back to back in `neighbours`, with
+// `starts[seat]..starts[seat + 1]` delimiting a seat's share. Occupancy is double buffered, so a step
+// reads one buffer, writes the other and swaps them without allocating.
+struct Simulator {
+    width: usize,
+    cells: Vec<Option<u32>>,
+    starts: Vec<usize>,
+    neighbours: Vec<u32>,
+    occupied: Vec<bool>,
+    next: Vec<bool>,
+    rules: Rules,
+    generation: usize,
+}
+
+impl Simulator {
+    fn new(layout: &Layout, rules: &Rules) -> Simulator {
+        let (width, height) = (layout.width(), layout.height());
+        let mut cells = vec![None; width * height];
+        let mut occupied = Vec::new();
+        for (y, row) in layout.seats.iter().enumerate() {
+            for (x, position) in row.iter().enumerate() {
+                if let Position::Seat(seat) = position {
+                    cells[y * width + x] = Some(occupied.len() as u32);
+                    occupied.push(*seat);
+                }
+            }
+        }
+
+        let mut starts = Vec::with_capacity(occupied.len() + 1);
+        let mut neighbours = Vec::with_capacity(occupied.len() * 8);
+        for y in 0..height {
+            for x in 0..width {
+                if cells[y * width + x].is_none() {
+                    continue;
+                }
+                starts.push(neighbours.len());
+                neighbours.extend(
+                    layout
+                        .neighbour_coordinates(x, y, &rules.neighbourhood)
+                        .iter()
+                        .filter_map(|&(x, y)| cells[y * width + x]),
+                );
+            }
+        }
+        starts.push(neighbours.len());
+
+        Simulator {
+            width,
+            cells,
+            starts,
+            neighbours,
+            next: occupied.clone(),
+            occupied,
+            rules: rules.clone(),
+            generation: 0,
+        }
+    }
+
+    // Returns how many seats changed; a step that changes nothing does not count as a generation.
+    fn step(&mut self) -> usize {
+        let mut next = mem::take(&mut self.next);
+        let changed = self.step_into(&self.occupied, &mut next);
+        self.next = mem::replace(&mut self.occupied, next);
+        if changed > 0 {
+            self.generation += 1;
+        }
+        changed
+    }
+
+    fn step_into(&self, occupied: &[bool], next: &mut [bool]) -> usize {
+        let mut changed = 0;
+        for seat in 0..occupied.len() {
+            let count = self.neighbours[self.starts[seat]..self.starts[seat + 1]]
+                .iter()
+                .map(|&neighbour| occupied[neighbour as usize] as usize)
+                .sum();
+            next[seat] = self.rules.next_occupancy(occupied[seat], count);
+            if next[seat] != occupied[seat] {
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    // Steps until nothing changes any more and returns the number of generations. Custom rules can
+    // make seats flip back and forth for ever, so every generation is remembered by the hash of its
+    // state. Keeping the states themselves would take too much memory for large layouts, so when a
+    // hash comes back the earlier generation is replayed from the start and compared in full.
+    fn settle(&mut self) -> Result<usize, Oscillation> {
+        let start = (self.generation, self.occupied.clone());
+        let mut seen = HashMap::new();
+        loop {
+            let mut hasher = DefaultHasher::new();
+            self.occupied
+                .chunks(64)
+                .map(|chunk| chunk.iter().fold(0, |bits, &seat| bits << 1 | seat as u64))
+                .for_each(|bits| hasher.write_u64(bits));
+            match seen.get(&hasher.finish()) {
+                Some(&first) if self.replay(&start, first) == self.occupied => {
+                    return Err(Oscillation {
+                        generation: first,
+                        period: self.generation - first,
+                    });
+                }
+                Some(_) => (),
+                None => {
+                    seen.insert(hasher.finish(), self.generation);
+                }
+            }
+            if self.step() == 0 {
+                return Ok(self.generation);
+            }
+        }
+    }
+
+    // The occupancy at `generation`, stepping on from a state recorded earlier.
+    fn replay(&self, (from, occupied): &(usize, Vec<bool>), generation: usize) -> Vec<bool> {
+        let mut occupied = occupied.clone();
+        let mut next = occupied.clone();
+        for _ in *from..generation {
+            self.step_into(&occupied, &mut next);
+            mem::swap(&mut occupied, &mut next);
+        }
+        occupied
+    }
+
+    fn nb_of_occupied_seats(&self) -> usize {
+        self.occupied.iter().filter(|&&occupied| occupied).count()
+    }
+
+    // Hands the starting state and every following generation to `observe`, stopping when the seats
+    // settle or after `limit` generations, and returns the statistics of each.
+    fn run(
+        &mut self,
+        limit: usize,
+        mut observe: impl FnMut(&Simulator, &GenerationStats),
+    ) -> Vector<GenerationStats> {
+        let initial = GenerationStats {
+            generation: self.generation,
+            occupied: self.nb_of_occupied_seats(),
+            changed: 0,
+        };
+        observe(self, &initial);
+        let mut history = vector!(initial);
+
+        for _ in 0..limit {
+            let changed = self.step();
+            if changed == 0 {
+                break;
+            }
+            let stats = GenerationStats {
+                generation: self.generation,
+                occupied: self.nb_of_occupied_seats(),
+                changed,
+            };
+            observe(self, &stats);
+            history.push_back(stats);
+        }
+        history
+    }
+
+    // A binary PPM with a `scale` by `scale` block of pixels per position.
+    fn to_ppm(&self, scale: usize) -> Vec<u8> {
+        let height = self.cells.len() / cmp::max(self.width, 1);
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width * scale, height * scale).into_bytes();
+        for row in self.cells.chunks(cmp::max(self.width, 1)) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|cell| {
+                    let colour = match cell {
+                        Some(seat) if self.occupied[*seat as usize] => OCCUPIED_COLOUR,
+                        Some(_) => EMPTY_COLOUR,
+                        None => FLOOR_COLOUR,
+                    };
+                    iter::repeat_n(colour, scale).flatten()
+                })
+                .collect();
+            for _ in 0..scale {
+                ppm.extend_from_slice(&line);
+            }
+        }
+        ppm
+    }
+
+    fn layout(&self) -> Layout {
+        let seats = self
+            .cells
+            .chunks(cmp::max(self.width, 1))
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        Some(seat) => Position::Seat(self.occupied[*seat as usize]),
+                        None => Position::Floor,
+                    })
+                    .collect()
+            })
+            .collect();
+        Layout { seats }
+    }
+}
+
+const FLOOR_COLOUR: [u8; 3] = [40, 40, 40];
+const EMPTY_COLOUR: [u8; 3] = [90, 160, 90];
+const OCCUPIED_COLOUR: [u8; 3] = [220, 70, 50];
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+struct GenerationStats {
+    generation: usize,
+    occupied: usize,
+    changed: usize,
+}
+
+impl fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Generation {}: {} occupied, {} changed",
+            self.generation, self.occupied, self.changed
+        )
+    }
+}
+
+fn history_to_csv(history: &Vector<GenerationStats>) -> String {
+    iter::once("generation,occupied,changed".to_owned())
+        .chain(
+            history
+                .iter()
+                .map(|stats| format!("{},{},{}", stats.generation, stats.occupied, stats.changed)),
+        )
+        .map(|line| line + "\n")
+        .collect()
+}
+
+// A square layout of empty seats with roughly one in eight positions floor, from a seeded generator
+// so that runs can be repeated.
+fn generate_layout(size: usize, seed: u64) -> Layout {
+    let mut random = utils::XorShift::new(seed);
+    let seats = (0..size)
+        .map(|_| {
+            (0..size)
+                .map(|_| {
+                    if random.next_u64().is_multiple_of(8) {
+                        Position::Floor
+                    } else {
+                        Position::Seat(false)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    Layout { seats }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Eq)]
+enum LayoutError {
+    RaggedRow {
+        row: usize,
+        length: usize,
+        width: usize,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RaggedRow { row, length, width } => write!(
+                f,
+                "Row {} has {} positions but the first row has {}",
+                row + 1,
+                length,
+                width
+            ),
+        }
+    }
+}
+
+// Every row has to be as wide as the first, since the simulator lays the positions out in one
+// block of `width` per row.
 impl TryFrom<&Vector<String>> for Layout {
-    type Error = ();
+    type Error = LayoutError;
 
     fn try_from(input: &Vector<String>) -> Result<Layout, Self::Error> {
-        let positions = input
+        let positions: Vector<Vector<Position>> = input
             .iter()
             .map(|line| {
                 line.chars()
@@ -292,20 +782,25 @@ impl TryFrom<&Vector<String>> for Layout {
                     .collect()
             })
             .collect();
+        let width = positions.head().map_or(0, |row| row.len());
+        if let Some((row, length)) = positions
+            .iter()
+            .map(|row| row.len())
+            .enumerate()
+            .find(|&(_, length)| length != width)
+        {
+            return Err(LayoutError::RaggedRow { row, length, width });
+        }
         Ok(Layout { seats: positions })
     }
 }
 
 impl fmt::Display for Layout {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut output = String::new();
-        for y in 0..self.height() {
-            for x in 0..self.width() {
-                output = format!("{}{}", output, self.get(x, y).unwrap());
-            }
-            output = format!("{}\n", output);
+        for row in self.seats.iter() {
+            writeln!(f, "{}", row.iter().join(""))?;
         }
-        writeln!(f, "{}", output)
+        writeln!(f)
     }
 }
 
@@ -333,7 +828,7 @@ mod test {
 
         let layout = Layout { seats };
 
-        let mut surroundings = layout.get_surroundings(1, 1);
+        let mut surroundings = layout.neighbours(1, 1, &Neighbourhood::Adjacent);
         surroundings.sort();
         let mut expected = vector!(
             &Position::Seat(false),
@@ -356,6 +851,23 @@ mod test {
         Layout::try_from(&lines).unwrap()
     }
 
+    #[test]
+    fn test_ragged_rows() {
+        let lines = vector!("L.L".to_owned(), "L.".to_owned(), "L.LL".to_owned());
+        assert_eq!(
+            Layout::try_from(&lines).err(),
+            Some(LayoutError::RaggedRow {
+                row: 1,
+                length: 2,
+                width: 3
+            })
+        );
+        assert_eq!(
+            Layout::try_from(&lines).unwrap_err().to_string(),
+            "Row 2 has 2 positions but the first row has 3"
+        );
+    }
+
     #[test]
     fn test_layout_directions_1() {
         let input = ".............\n\
@@ -364,7 +876,8 @@ mod test {
 
         let layout = parse(input);
         println!("{}", layout);
-        let mut surroundings = layout.get_directions(1, 1);
+        let sight = Neighbourhood::LineOfSight { max_distance: None };
+        let mut surroundings = layout.neighbours(1, 1, &sight);
         surroundings.sort();
         let mut expected = vector!(&Position::Seat(false));
         expected.sort();
@@ -379,7 +892,8 @@ mod test {
                      #.#L";
 
         let layout = parse(input);
-        let mut surroundings = layout.get_directions(0, 0);
+        let sight = Neighbourhood::LineOfSight { max_distance: None };
+        let mut surroundings = layout.neighbours(0, 0, &sight);
         surroundings.sort();
         let mut expected = vector!(
             &Position::Seat(true),
@@ -414,7 +928,7 @@ mod test {
                      ##########\n\
                      #.######.#\n\
                      #.#####.##";
-        let result1 = layout.next_v2().unwrap();
+        let result1 = layout.step(&Rules::line_of_sight()).unwrap();
         assert_eq!(result1, parse(step1));
 
         let step2 = "#.LL.LL.L#\n\
@@ -427,7 +941,128 @@ mod test {
                      LLLLLLLLL#\n\
                      #.LLLLLL.L\n\
                      #.LLLLL.L#";
-        let result2 = result1.next_v2().unwrap();
+        let result2 = result1.step(&Rules::line_of_sight()).unwrap();
         assert_eq!(result2, parse(step2));
     }
+
+    #[test]
+    fn test_rules() {
+        let layout = parse(
+            "L.L.L\n\
+             .....\n\
+             L.#.L",
+        );
+        let near = Neighbourhood::LineOfSight {
+            max_distance: Some(1),
+        };
+        assert_eq!(layout.neighbours(2, 0, &near).len(), 0);
+        let far = Neighbourhood::LineOfSight {
+            max_distance: Some(2),
+        };
+        assert_eq!(layout.neighbours(2, 0, &far).len(), 5);
+        let knight = Neighbourhood::try_from("offsets:1,2;-2,1").unwrap();
+        assert_eq!(knight, Neighbourhood::Offsets(vector!((1, 2), (-2, 1))));
+        assert_eq!(layout.neighbours(0, 0, &knight), vector!(&Position::Floor));
+
+        let input = utils::read_strings("input/test.txt").unwrap();
+        let layout = Layout::try_from(&input).unwrap();
+        let settled = |rules: &Rules| layout.settle(rules).unwrap().nb_of_occupied_seats();
+        assert_eq!(settled(&Rules::adjacent()), 37);
+        assert_eq!(settled(&Rules::line_of_sight()), 26);
+
+        // Two seats side by side fill together and then empty together, for ever.
+        let pair = parse("LL");
+        let flipping = Rules::from_params("offsets:1,0;-1,0", Some("0"), Some("1")).unwrap();
+        assert_eq!(
+            pair.settle(&flipping),
+            Err(Oscillation {
+                generation: 0,
+                period: 2
+            })
+        );
+
+        let rules = Rules::from_params("sight:3", None, Some("6")).unwrap();
+        assert_eq!(rules.occupy_at_most, 0);
+        assert_eq!(rules.vacate_at_least, 6);
+        assert_eq!(
+            rules.to_string(),
+            "sight:3 neighbours, occupying at most 0 and vacating at least 6"
+        );
+        assert_eq!(
+            Rules::from_params("offsets:0,0", None, None),
+            Err(RuleError::InvalidOffset("0,0".to_owned()))
+        );
+        assert_eq!(
+            Neighbourhood::try_from("offsets:1,1;0,0"),
+            Err(RuleError::InvalidOffset("0,0".to_owned()))
+        );
+        assert_eq!(
+            Rules::from_params("knight", None, None),
+            Err(RuleError::UnknownNeighbourhood("knight".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_simulator() {
+        let layout = generate_layout(40, 11);
+        let mut rule_sets = vector!(Rules::adjacent(), Rules::line_of_sight());
+        rule_sets.push_back(Rules::from_params("sight:3", Some("1"), Some("4")).unwrap());
+        rule_sets
+            .push_back(Rules::from_params("offsets:0,-2;2,0;0,2;-2,0", None, Some("3")).unwrap());
+
+        for rules in rule_sets {
+            let mut simulator = Simulator::new(&layout, &rules);
+            let mut reference = Some(layout.clone());
+            for _ in 0..6 {
+                let changed = simulator.step();
+                let expected = reference.as_ref().unwrap().step(&rules);
+                assert_eq!(changed > 0, expected.is_some());
+                reference = expected.or(reference);
+                assert_eq!(simulator.layout(), *reference.as_ref().unwrap());
+            }
+        }
+
+        let input = utils::read_strings("input/test.txt").unwrap();
+        let mut simulator = Simulator::new(&Layout::try_from(&input).unwrap(), &Rules::adjacent());
+        let start = (0, simulator.occupied.clone());
+        assert_eq!(simulator.settle(), Ok(4));
+        assert_eq!(simulator.nb_of_occupied_seats(), 37);
+        assert_eq!(simulator.replay(&start, 4), simulator.occupied);
+        assert_ne!(simulator.replay(&start, 3), simulator.occupied);
+    }
+
+    #[test]
+    fn test_recording() {
+        let input = utils::read_strings("input/test.txt").unwrap();
+        let layout = Layout::try_from(&input).unwrap();
+        let mut simulator = Simulator::new(&layout, &Rules::adjacent());
+        let mut frames = Vector::new();
+        let history = simulator.run(10, |simulator, _| frames.push_back(simulator.layout()));
+
+        assert_eq!(history.len(), 5);
+        assert_eq!(frames.len(), history.len());
+        assert_eq!(frames[0], layout);
+        assert_eq!(
+            history[1],
+            GenerationStats {
+                generation: 1,
+                occupied: 20,
+                changed: 51
+            }
+        );
+        assert_eq!(history.last().unwrap().occupied, 37);
+
+        let csv = history_to_csv(&history);
+        assert_eq!(csv.lines().next(), Some("generation,occupied,changed"));
+        assert_eq!(csv.lines().nth(1), Some("0,71,0"));
+
+        let ppm = simulator.to_ppm(2);
+        let header = "P6\n20 20\n255\n";
+        assert!(ppm.starts_with(header.as_bytes()));
+        assert_eq!(ppm.len(), header.len() + 20 * 20 * 3);
+        assert_eq!(ppm[header.len()..header.len() + 3], OCCUPIED_COLOUR);
+
+        let mut limited = Simulator::new(&layout, &Rules::adjacent());
+        assert_eq!(limited.run(2, |_, _| ()).len(), 3);
+    }
 }
@@ -3,26 +3,57 @@ extern crate im_rc;
 extern crate lazy_static;
 extern crate regex;
 
+use im_rc::vector;
 use im_rc::Vector;
+use itertools::Itertools;
 use regex::Regex;
+use std::cmp;
 use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
 
 fn main() {
     println!("--- [AoC 2020] Day 12: Rain Risk ---");
     let input = utils::read_strings_from_param();
     part_one(&input);
     part_two(&input);
+
+    let args: Vector<String> = env::args().collect();
+    let param = |name: &str| args.iter().skip_while(|arg| *arg != name).nth(1);
+    let actions = parse_actions(&input);
+    let tracks = vector!(
+        ("ship", navigate(Ship::new(), &actions)),
+        (
+            "waypoint",
+            navigate(WaypointShip::new(&Point::new(10, 1)), &actions)
+        )
+    );
+
+    if let Some(name) = param("--csv") {
+        let (_, track) = tracks
+            .iter()
+            .find(|(track_name, _)| track_name == name)
+            .expect("--csv takes ship or waypoint");
+        print!("{}", track.to_csv());
+    }
+    if let Some(path) = param("--svg") {
+        fs::write(path, to_svg(&tracks)).expect("Failed to write SVG");
+        println!("Tracks written to {}", path);
+    }
 }
 
-fn part_one(input: &Vector<String>) {
-    let actions = input.iter().map(|l| Action::try_from(&l[..]).unwrap());
-    let ship = Ship::new();
-    let final_position = actions.fold(ship, |mut sh, action| {
-        sh.take_action(&action);
-        sh
-    });
+fn parse_actions(input: &Vector<String>) -> Vector<Action> {
+    input
+        .iter()
+        .map(|l| Action::try_from(&l[..]).unwrap_or_else(|error| panic!("{}", error)))
+        .collect()
+}
 
-    let result = final_position
+fn part_one(input: &Vector<String>) {
+    let track = navigate(Ship::new(), &parse_actions(input));
+    let result = track
+        .last()
         .position
         .manhatten_distance_to(&Point::origin());
 
@@ -30,20 +61,115 @@ fn part_one(input: &Vector<String>) {
 }
 
 fn part_two(input: &Vector<String>) {
-    let actions = input.iter().map(|l| Action::try_from(&l[..]).unwrap());
-    let ship = WaypointShip::new(&Point::new(10, 1));
-    let final_position = actions.fold(ship, |mut sh, action| {
-        sh.take_action(&action);
-        sh
-    });
-
-    let result = final_position
+    let track = navigate(WaypointShip::new(&Point::new(10, 1)), &parse_actions(input));
+    let result = track
+        .last()
         .position
         .manhatten_distance_to(&Point::origin());
 
     println!("Result of part two: {}", result);
 }
 
+trait Navigator {
+    fn take_action(&mut self, action: &Action);
+    fn snapshot(&self, action: Option<Action>) -> TrackPoint;
+}
+
+fn navigate<N: Navigator>(mut navigator: N, actions: &Vector<Action>) -> Track {
+    let start = navigator.snapshot(None);
+    let points = std::iter::once(start)
+        .chain(actions.iter().map(|action| {
+            navigator.take_action(action);
+            navigator.snapshot(Some(*action))
+        }))
+        .collect();
+    Track { points }
+}
+
+// The state after an action; the first point of a track is the start and has no action.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct TrackPoint {
+    action: Option<Action>,
+    position: Point,
+    heading: Option<i32>,
+    waypoint: Option<Point>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Track {
+    points: Vector<TrackPoint>,
+}
+
+impl Track {
+    fn last(&self) -> &TrackPoint {
+        self.points.last().unwrap()
+    }
+
+    fn to_csv(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        std::iter::once("step,action,x,y,heading,waypoint_x,waypoint_y".to_owned())
+            .chain(self.points.iter().enumerate().map(|(step, point)| {
+                format!(
+                    "{},{},{},{},{},{},{}",
+                    step,
+                    optional(point.action.map(|action| action.to_string())),
+                    point.position.x,
+                    point.position.y,
+                    optional(point.heading.map(|heading| heading.to_string())),
+                    optional(point.waypoint.map(|waypoint| waypoint.x.to_string())),
+                    optional(point.waypoint.map(|waypoint| waypoint.y.to_string()))
+                )
+            }))
+            .map(|line| line + "\n")
+            .collect()
+    }
+}
+
+const TRACK_COLOURS: [&str; 4] = ["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e"];
+
+// Draws every track as a polyline in one picture so navigators can be compared. North is up, which
+// means flipping y; the margin keeps the start and end markers inside the view box.
+fn to_svg(tracks: &Vector<(&str, Track)>) -> String {
+    let positions = || {
+        tracks
+            .iter()
+            .flat_map(|(_, track)| track.points.iter().map(|point| point.position))
+    };
+    let min_x = positions().map(|p| p.x).min().unwrap_or(0);
+    let max_x = positions().map(|p| p.x).max().unwrap_or(0);
+    let min_y = positions().map(|p| -p.y).min().unwrap_or(0);
+    let max_y = positions().map(|p| -p.y).max().unwrap_or(0);
+    let margin = cmp::max(cmp::max(max_x - min_x, max_y - min_y) / 20, 1);
+
+    let lines = tracks.iter().enumerate().map(|(index, (name, track))| {
+        let points = track
+            .points
+            .iter()
+            .map(|point| format!("{},{}", point.position.x, -point.position.y))
+            .join(" ");
+        format!(
+            "  <polyline id=\"{}\" points=\"{}\" fill=\"none\" stroke=\"{}\" \
+             stroke-width=\"2\" vector-effect=\"non-scaling-stroke\"><title>{}</title></polyline>",
+            name,
+            points,
+            TRACK_COLOURS[index % TRACK_COLOURS.len()],
+            name
+        )
+    });
+
+    std::iter::once(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\" width=\"800\" height=\"800\">",
+        min_x - margin,
+        min_y - margin,
+        max_x - min_x + 2 * margin,
+        max_y - min_y + 2 * margin
+    ))
+    .chain(lines)
+    .chain(std::iter::once("</svg>".to_owned()))
+    .map(|line| line + "\n")
+    .collect()
+}
+
 #[derive(Copy, PartialEq, Debug, Clone)]
 enum Action {
     Move(i32, Direction),
@@ -52,19 +178,27 @@ enum Action {
     Forward(i32),
 }
 
+// Turns are reduced to a single revolution first, so that any i32 angle the parser accepts can be
+// added to a heading or negated without overflowing.
 impl Action {
-    fn applied_to(&self, ship: &Ship) -> (Point, Direction) {
+    fn applied_to(&self, ship: &Ship) -> (Point, i32) {
         match self {
             Self::Forward(distance) => (
-                ship.position.move_in_direction(&ship.direction, distance),
-                ship.direction,
+                ship.position.move_at_angle(ship.heading, distance),
+                ship.heading,
             ),
             Self::Move(distance, direction) => (
-                ship.position.move_in_direction(&direction, distance),
-                ship.direction,
+                ship.position.move_in_direction(direction, distance),
+                ship.heading,
+            ),
+            Self::TurnLeft(angle) => (
+                ship.position,
+                (ship.heading + angle.rem_euclid(360)).rem_euclid(360),
+            ),
+            Self::TurnRight(angle) => (
+                ship.position,
+                (ship.heading - angle.rem_euclid(360)).rem_euclid(360),
             ),
-            Self::TurnLeft(angle) => (ship.position, ship.direction.turn_left(angle)),
-            Self::TurnRight(angle) => (ship.position, ship.direction.turn_right(angle)),
         }
     }
 
@@ -72,40 +206,72 @@ impl Action {
         match self {
             Self::Forward(_) => *waypoint,
             Self::Move(distance, direction) => waypoint.move_in_direction(direction, distance),
-            Self::TurnLeft(angle) => waypoint.rotate_around(position, angle),
-            Self::TurnRight(angle) => waypoint.rotate_around(position, &(-1 * angle)),
+            Self::TurnLeft(angle) => waypoint.rotate_around(position, &angle.rem_euclid(360)),
+            Self::TurnRight(angle) => {
+                waypoint.rotate_around(position, &(360 - angle.rem_euclid(360)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Move(distance, direction) => write!(f, "{:?}{}", direction, distance),
+            Self::TurnLeft(angle) => write!(f, "L{}", angle),
+            Self::TurnRight(angle) => write!(f, "R{}", angle),
+            Self::Forward(distance) => write!(f, "F{}", distance),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ParseError {
+    Syntax(String),
+    UnknownCommand(String),
+    ValueOutOfRange(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Syntax(input) => {
+                write!(f, "'{}' should be a command followed by a number", input)
+            }
+            ParseError::UnknownCommand(command) => write!(f, "Unknown command '{}'", command),
+            ParseError::ValueOutOfRange(value) => write!(f, "Value {} is out of range", value),
         }
     }
 }
 
+// Commands are the puzzle's letters or their full names in any case, optionally separated from a
+// signed value by whitespace. A negative value does the opposite: L-90 is R90 and F-5 backs up.
 impl TryFrom<&str> for Action {
-    type Error = ();
+    type Error = ParseError;
 
     fn try_from(input: &str) -> Result<Action, Self::Error> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"^(?P<action>[NSEWLRF])(?P<value>[0-9]+)$").unwrap();
+            static ref RE: Regex =
+                Regex::new(r"^\s*(?P<action>[A-Za-z]+)\s*(?P<value>[+-]?[0-9]+)\s*$").unwrap();
         }
 
-        let action = RE.captures(input).and_then(|matches| {
-            let value = matches
-                .name("value")
-                .unwrap()
-                .as_str()
-                .parse::<i32>()
-                .unwrap();
-            match matches.name("action").unwrap().as_str() {
-                "N" => Some(Action::Move(value, Direction::N)),
-                "S" => Some(Action::Move(value, Direction::S)),
-                "E" => Some(Action::Move(value, Direction::E)),
-                "W" => Some(Action::Move(value, Direction::W)),
-                "L" => Some(Action::TurnLeft(value)),
-                "R" => Some(Action::TurnRight(value)),
-                "F" => Some(Action::Forward(value)),
-                _ => None,
-            }
-        });
-
-        action.ok_or(())
+        let matches = RE
+            .captures(input)
+            .ok_or_else(|| ParseError::Syntax(input.to_owned()))?;
+        let value = &matches["value"];
+        let value = value
+            .parse::<i32>()
+            .map_err(|_| ParseError::ValueOutOfRange(value.to_owned()))?;
+        match matches["action"].to_uppercase().as_str() {
+            "N" | "NORTH" => Ok(Action::Move(value, Direction::N)),
+            "S" | "SOUTH" => Ok(Action::Move(value, Direction::S)),
+            "E" | "EAST" => Ok(Action::Move(value, Direction::E)),
+            "W" | "WEST" => Ok(Action::Move(value, Direction::W)),
+            "L" | "LEFT" => Ok(Action::TurnLeft(value)),
+            "R" | "RIGHT" => Ok(Action::TurnRight(value)),
+            "F" | "FORWARD" => Ok(Action::Forward(value)),
+            _ => Err(ParseError::UnknownCommand(matches["action"].to_owned())),
+        }
     }
 }
 
@@ -137,16 +303,9 @@ impl Direction {
     fn delta(&self) -> (i32, i32) {
         (self.dx(), self.dy())
     }
-
-    fn turn_left(&self, angle: &i32) -> Direction {
-        Direction::from(i32::from(self) + angle)
-    }
-
-    fn turn_right(&self, angle: &i32) -> Direction {
-        Direction::from(i32::from(self) - angle)
-    }
 }
 
+// The compass direction nearest to a heading, for when only the four winds will do.
 impl From<i32> for Direction {
     fn from(angle: i32) -> Direction {
         match angle.rem_euclid(360) {
@@ -192,23 +351,26 @@ impl Point {
         dx.abs() + dy.abs()
     }
 
+    // Rotates counterclockwise and rounds to the nearest grid point, so only quarter turns are
+    // lossless and repeated odd turns can drift.
     fn rotate_around(&self, other: &Point, angle: &i32) -> Point {
-        match angle.rem_euclid(360) / 90 {
-            0 => *self,
-            1 => {
-                let (dx, dy) = other.delta(self);
-                Point::new(other.x - dy, other.y + dx)
-            }
-            2 => {
-                let (dx, dy) = other.delta(self);
-                Point::new(other.x - dx, other.y - dy)
-            }
-            3 => {
-                let (dx, dy) = other.delta(self);
-                Point::new(other.x + dy, other.y - dx)
-            }
-            _ => panic!("Failed to rotate {} degrees", angle),
-        }
+        let (cos, sin) = unit_vector(*angle);
+        let (dx, dy) = other.delta(self);
+        let (dx, dy) = (dx as f64, dy as f64);
+        Point::new(
+            other.x + round_to_grid(dx * cos - dy * sin),
+            other.y + round_to_grid(dx * sin + dy * cos),
+        )
+    }
+
+    // Each move lands on the grid point nearest to where the heading would take it.
+    fn move_at_angle(&self, angle: i32, distance: &i32) -> Point {
+        let (cos, sin) = unit_vector(angle);
+        let distance = *distance as f64;
+        Point::new(
+            self.x + round_to_grid(distance * cos),
+            self.y + round_to_grid(distance * sin),
+        )
     }
 
     fn move_in_direction(&self, direction: &Direction, distance: &i32) -> Point {
@@ -220,24 +382,55 @@ impl Point {
     }
 }
 
+// Headings are in degrees counterclockwise from east. Quarter turns are looked up rather than
+// computed so the puzzle's own actions stay exact.
+fn unit_vector(angle: i32) -> (f64, f64) {
+    match angle.rem_euclid(360) {
+        0 => (1.0, 0.0),
+        90 => (0.0, 1.0),
+        180 => (-1.0, 0.0),
+        270 => (0.0, -1.0),
+        other => {
+            let radians = (other as f64).to_radians();
+            (radians.cos(), radians.sin())
+        }
+    }
+}
+
+// Halfway cases round away from zero.
+fn round_to_grid(value: f64) -> i32 {
+    value.round() as i32
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 struct Ship {
     position: Point,
-    direction: Direction,
+    heading: i32,
 }
 
 impl Ship {
     fn new() -> Ship {
         Ship {
             position: Point::origin(),
-            direction: Direction::E,
+            heading: i32::from(&Direction::E),
         }
     }
+}
 
+impl Navigator for Ship {
     fn take_action(&mut self, action: &Action) {
-        let (new_position, new_direction) = action.applied_to(&self);
+        let (new_position, new_heading) = action.applied_to(self);
         self.position = new_position;
-        self.direction = new_direction;
+        self.heading = new_heading;
+    }
+
+    fn snapshot(&self, action: Option<Action>) -> TrackPoint {
+        TrackPoint {
+            action,
+            position: self.position,
+            heading: Some(self.heading),
+            waypoint: None,
+        }
     }
 }
 
@@ -254,7 +447,9 @@ impl WaypointShip {
             waypoint: *waypoint,
         }
     }
+}
 
+impl Navigator for WaypointShip {
     fn take_action(&mut self, action: &Action) {
         match action {
             Action::Forward(distance) => {
@@ -267,6 +462,15 @@ impl WaypointShip {
             }
         }
     }
+
+    fn snapshot(&self, action: Option<Action>) -> TrackPoint {
+        TrackPoint {
+            action,
+            position: self.position,
+            heading: None,
+            waypoint: Some(self.waypoint),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -298,4 +502,112 @@ mod test {
         assert_eq!(Direction::from(270 - 44), Direction::S);
         assert_eq!(Direction::from(270 - 10 + 360), Direction::S);
     }
+
+    #[test]
+    fn test_tracks() {
+        let actions = parse_actions(&utils::read_strings("input/test.txt").unwrap());
+        let ship = navigate(Ship::new(), &actions);
+        let waypoint = navigate(WaypointShip::new(&Point::new(10, 1)), &actions);
+        assert_eq!(ship.points.len(), actions.len() + 1);
+        assert_eq!(ship.last().position, Point::new(17, -8));
+        assert_eq!(ship.last().heading, Some(270));
+        assert_eq!(waypoint.last().position, Point::new(214, -72));
+        assert_eq!(waypoint.last().waypoint, Some(Point::new(218, -82)));
+
+        let csv = ship.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "step,action,x,y,heading,waypoint_x,waypoint_y");
+        assert_eq!(lines[1], "0,,0,0,0,,");
+        assert_eq!(lines[2], "1,F10,10,0,0,,");
+        assert_eq!(
+            waypoint.to_csv().lines().nth(2),
+            Some("1,F10,100,10,,110,11")
+        );
+
+        let svg = to_svg(&vector!(("ship", ship), ("waypoint", waypoint)));
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(svg.matches("<polyline").count(), 2);
+        assert!(svg.contains("points=\"0,0 10,0 10,-3 17,-3 17,-3 17,8\""));
+    }
+
+    #[test]
+    fn test_parse_actions() {
+        assert_eq!(Action::try_from("F10"), Ok(Action::Forward(10)));
+        assert_eq!(Action::try_from("forward 10"), Ok(Action::Forward(10)));
+        assert_eq!(Action::try_from("Left -45"), Ok(Action::TurnLeft(-45)));
+        assert_eq!(
+            Action::try_from("SOUTH+3"),
+            Ok(Action::Move(3, Direction::S))
+        );
+        assert_eq!(
+            Action::try_from("X3"),
+            Err(ParseError::UnknownCommand("X".to_owned()))
+        );
+        assert_eq!(
+            Action::try_from("F"),
+            Err(ParseError::Syntax("F".to_owned()))
+        );
+        assert_eq!(
+            Action::try_from("F99999999999"),
+            Err(ParseError::ValueOutOfRange("99999999999".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_arbitrary_angles() {
+        let actions = parse_actions(&vector!(
+            "L45".to_owned(),
+            "F10".to_owned(),
+            "R-45".to_owned(),
+            "F-2".to_owned(),
+            "L30".to_owned(),
+            "F100".to_owned()
+        ));
+        let track = navigate(Ship::new(), &actions);
+        let positions: Vec<Point> = track.points.iter().map(|point| point.position).collect();
+        assert_eq!(positions[2], Point::new(7, 7));
+        assert_eq!(positions[4], Point::new(7, 5));
+        assert_eq!(positions[6], Point::new(-43, 92));
+        assert_eq!(track.last().heading, Some(120));
+
+        let origin = Point::origin();
+        assert_eq!(
+            Point::new(10, 0).rotate_around(&origin, &60),
+            Point::new(5, 9)
+        );
+        assert_eq!(
+            Point::new(10, 0).rotate_around(&origin, &-60),
+            Point::new(5, -9)
+        );
+        assert_eq!(
+            Point::new(3, 4).rotate_around(&origin, &450),
+            Point::new(-4, 3)
+        );
+        assert_eq!(
+            Point::new(12, 5).rotate_around(&Point::new(2, 5), &-90),
+            Point::new(2, -5)
+        );
+
+        // 2147483647 is 127 degrees past a whole number of turns, and -2147483648 is 128 short.
+        let ship = Ship::new();
+        let waypoint = Point::new(10, 1);
+        let extreme_left = Action::try_from("L2147483647").unwrap();
+        let extreme_right = Action::try_from("R-2147483648").unwrap();
+        assert_eq!(
+            extreme_left.applied_to(&ship),
+            Action::TurnLeft(127).applied_to(&ship)
+        );
+        assert_eq!(
+            extreme_right.applied_to(&ship),
+            Action::TurnLeft(128).applied_to(&ship)
+        );
+        assert_eq!(
+            extreme_left.applied_to_waypoint(&ship.position, &waypoint),
+            Action::TurnLeft(127).applied_to_waypoint(&ship.position, &waypoint)
+        );
+        assert_eq!(
+            extreme_right.applied_to_waypoint(&ship.position, &waypoint),
+            Action::TurnLeft(128).applied_to_waypoint(&ship.position, &waypoint)
+        );
+    }
 }
@@ -1,53 +1,294 @@
 extern crate im_rc;
 
 use im_rc::Vector;
+use itertools::Itertools;
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::One;
+use num_traits::Zero;
+use std::env;
+use std::fmt;
 
 fn main() {
     println!("--- [AoC 2020] Day 13: Shuttle Search ---");
     let input = utils::read_strings_from_param();
+    let schedule = Schedule::parse(&input).unwrap_or_else(|e| panic!("{}", e));
     println!("Solution to part one: {}", part_one(&input).unwrap());
-    println!("Solution to part two: {}", part_two(&input).unwrap());
+    match part_two(&input) {
+        Ok(solution) => println!("Solution to part two: {}", solution),
+        Err(error) => println!("Part two: {}", error),
+    }
+
+    let args: Vector<String> = env::args().collect();
+    let param = |name: &str| args.iter().skip_while(|arg| *arg != name).nth(1);
+    let range = |name: &str| {
+        param(name).map(|range| {
+            let (from, to) = range
+                .split("..")
+                .map(|t| t.parse::<u64>().expect("Ranges look like 100..200"))
+                .collect_tuple()
+                .expect("Ranges look like 100..200");
+            (from, to)
+        })
+    };
+
+    if let Some(time) = param("--next") {
+        let time = time.parse().expect("--next takes a timestamp");
+        schedule
+            .waits(time)
+            .iter()
+            .for_each(|(bus, wait)| println!("bus {:>4} leaves in {:>4} minutes", bus.id, wait));
+        if let Some((bus, wait)) = schedule.next_bus(time) {
+            println!("Next bus after {} is {} in {} minutes", time, bus.id, wait);
+        }
+    }
+    if let Some((from, to)) = range("--departures") {
+        schedule
+            .departures(from, to)
+            .iter()
+            .for_each(|(timestamp, bus)| println!("{}: bus {}", timestamp, bus.id));
+    }
+    if let Some((from, to)) = range("--timetable") {
+        print!("{}", schedule.timetable(from, to));
+    }
+    if let Some(subset) = param("--align") {
+        let ids: Vector<u64> = subset
+            .split(',')
+            .map(|id| id.parse().expect("Bus ids should be numbers"))
+            .collect();
+        let after = param("--after")
+            .map(|after| after.parse().expect("--after should be a number"))
+            .unwrap_or_else(BigInt::zero);
+        let count = param("--count").map_or(5, |count| count.parse().unwrap());
+
+        let selected: Vector<Bus> = schedule
+            .busses
+            .iter()
+            .copied()
+            .filter(|bus| ids.contains(&bus.id))
+            .collect();
+        match alignments(&selected, &after, count) {
+            Ok(timestamps) => println!(
+                "Busses {} align at {}",
+                selected.iter().map(|bus| bus.id).join(", "),
+                timestamps.iter().join(", ")
+            ),
+            Err(error) => println!("{}", error),
+        }
+    }
 }
 
-fn part_one(input: &Vector<String>) -> Option<usize> {
-    let departure_time = input.head().unwrap().parse::<usize>().unwrap();
-    let busses = input
-        .last()
-        .unwrap()
-        .split(',')
-        .filter(|&a| a != "x")
-        .map(|w| w.parse::<usize>().unwrap());
+fn part_one(input: &Vector<String>) -> Option<u64> {
+    let schedule = Schedule::parse(input).ok()?;
+    schedule
+        .next_bus(schedule.earliest)
+        .map(|(bus, wait)| bus.id * wait)
+}
 
-    busses
-        .map(|bus_id| (bus_id, calculate_wait_time(&departure_time, &bus_id)))
-        .min_by_key(|(_, wait)| *wait)
-        .map(|(bus_id, wait)| bus_id * wait)
+fn part_two(input: &Vector<String>) -> Result<BigInt, ScheduleError> {
+    let busses = parse_busses(input.last().ok_or(ScheduleError::MissingLine)?)?;
+    solve(&busses).map(|congruence| congruence.residue)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Schedule {
+    earliest: u64,
+    busses: Vector<Bus>,
+}
+
+impl Schedule {
+    fn parse(input: &Vector<String>) -> Result<Schedule, ScheduleError> {
+        match (input.get(0), input.get(1)) {
+            (Some(earliest), Some(busses)) => Ok(Schedule {
+                earliest: earliest
+                    .parse()
+                    .map_err(|_| ScheduleError::InvalidTimestamp(earliest.to_owned()))?,
+                busses: parse_busses(busses)?,
+            }),
+            _ => Err(ScheduleError::MissingLine),
+        }
+    }
+
+    // Every (timestamp, bus) departure in from..=to, ordered by time and then by bus.
+    fn departures(&self, from: u64, to: u64) -> Vector<(u64, Bus)> {
+        self.busses
+            .iter()
+            .flat_map(|&bus| {
+                let first = from.div_ceil(bus.id) * bus.id;
+                (first..=to)
+                    .step_by(bus.id as usize)
+                    .map(move |timestamp| (timestamp, bus))
+            })
+            .sorted_by_key(|&(timestamp, _)| timestamp)
+            .collect()
+    }
+
+    // A bus leaving exactly at `time` is waited for until its next loop, as in part one.
+    fn waits(&self, time: u64) -> Vector<(Bus, u64)> {
+        self.busses
+            .iter()
+            .map(|&bus| (bus, calculate_wait_time(&time, &bus.id)))
+            .collect()
+    }
+
+    // The bus with the shortest wait at `time`; the first listed bus wins a tie.
+    fn next_bus(&self, time: u64) -> Option<(Bus, u64)> {
+        self.waits(time).into_iter().min_by_key(|&(_, wait)| wait)
+    }
+
+    fn timetable(&self, from: u64, to: u64) -> String {
+        let header = std::iter::once(format!("{:<12}", "time"))
+            .chain(
+                self.busses
+                    .iter()
+                    .map(|bus| format!("{:^8}", format!("bus {}", bus.id))),
+            )
+            .join("")
+            .trim_end()
+            .to_owned();
+        let rows =
+            (from..=to).map(|timestamp| {
+                std::iter::once(format!("{:<12}", timestamp))
+                    .chain(self.busses.iter().map(|bus| {
+                        format!("{:^8}", if timestamp % bus.id == 0 { "D" } else { "." })
+                    }))
+                    .join("")
+                    .trim_end()
+                    .to_owned()
+            });
+        std::iter::once(header)
+            .chain(rows)
+            .map(|line| line + "\n")
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Bus {
+    id: u64,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ScheduleError {
+    MissingLine,
+    InvalidTimestamp(String),
+    InvalidBus(String),
+    NoBusses,
+    NoSolution { bus: Bus, constraints: Vector<Bus> },
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::MissingLine => {
+                write!(f, "A schedule needs a timestamp line and a bus line")
+            }
+            ScheduleError::InvalidTimestamp(line) => write!(f, "'{}' is not a timestamp", line),
+            ScheduleError::InvalidBus(bus) => write!(f, "'{}' is not a bus id", bus),
+            ScheduleError::NoBusses => write!(f, "No busses to align"),
+            ScheduleError::NoSolution { bus, constraints } => write!(
+                f,
+                "No solution: bus {} at offset {} can never align with busses {}",
+                bus.id,
+                bus.offset,
+                constraints.iter().map(|bus| bus.id).join(", ")
+            ),
+        }
+    }
 }
 
-fn part_two(input: &Vector<String>) -> Option<usize> {
-    let busses: Vector<_> = input
-        .last()
-        .unwrap()
-        .split(',')
-        .map(|l| {
-            Some(l)
-                .filter(|&b| b != "x")
-                .map(|i| i.parse::<usize>().unwrap())
+fn parse_busses(line: &str) -> Result<Vector<Bus>, ScheduleError> {
+    line.split(',')
+        .enumerate()
+        .filter(|(_, id)| *id != "x")
+        .map(|(offset, id)| match id.parse::<u64>() {
+            Ok(id) if id > 0 => Ok(Bus {
+                id,
+                offset: offset as u64,
+            }),
+            _ => Err(ScheduleError::InvalidBus(id.to_owned())),
         })
+        .collect()
+}
+
+// t ≡ residue (mod modulus), with the residue kept in 0..modulus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Congruence {
+    residue: BigInt,
+    modulus: BigInt,
+}
+
+impl Congruence {
+    fn for_bus(bus: &Bus) -> Congruence {
+        let modulus = BigInt::from(bus.id);
+        Congruence {
+            residue: (-BigInt::from(bus.offset)).mod_floor(&modulus),
+            modulus,
+        }
+    }
+
+    // Generalised Chinese remainder theorem: the moduli may share factors, in which case the
+    // residues have to agree modulo their gcd or the two congruences have no common solution.
+    fn combine(&self, other: &Congruence) -> Option<Congruence> {
+        let gcd = self.modulus.extended_gcd(&other.modulus);
+        let difference = &other.residue - &self.residue;
+        if !difference.is_multiple_of(&gcd.gcd) {
+            return None;
+        }
+
+        let step = &other.modulus / &gcd.gcd;
+        let k = (difference / &gcd.gcd * gcd.x).mod_floor(&step);
+        let modulus = &self.modulus * &step;
+        Some(Congruence {
+            residue: (&self.residue + &self.modulus * k).mod_floor(&modulus),
+            modulus,
+        })
+    }
+
+    // The first timestamp at or after `after` that satisfies the congruence.
+    fn first_after(&self, after: &BigInt) -> BigInt {
+        after + (&self.residue - after).mod_floor(&self.modulus)
+    }
+}
+
+fn solve(busses: &Vector<Bus>) -> Result<Congruence, ScheduleError> {
+    if busses.is_empty() {
+        return Err(ScheduleError::NoBusses);
+    }
+    let initial = Congruence {
+        residue: BigInt::zero(),
+        modulus: BigInt::one(),
+    };
+    busses
+        .iter()
         .enumerate()
-        .filter_map(|(offset, bus)| bus.map(|id| (id, offset)))
-        .collect();
-
-    let (solution, _) = busses.iter().fold((0, 1), |(t, prod), (bus_id, offset)| {
-        let next_timestamp = std::iter::successors(Some(t), |i| Some(i + prod))
-            .find(|t| (t + offset) % bus_id == 0)
-            .unwrap();
-        (next_timestamp, prod * bus_id)
-    });
-    Some(solution)
+        .try_fold(initial, |congruence, (index, bus)| {
+            congruence
+                .combine(&Congruence::for_bus(bus))
+                .ok_or_else(|| ScheduleError::NoSolution {
+                    bus: *bus,
+                    constraints: busses.take(index),
+                })
+        })
 }
 
-fn calculate_wait_time(departure_time: &usize, loop_duration: &usize) -> usize {
+fn alignments(
+    busses: &Vector<Bus>,
+    after: &BigInt,
+    count: usize,
+) -> Result<Vector<BigInt>, ScheduleError> {
+    let congruence = solve(busses)?;
+    let first = congruence.first_after(after);
+    Ok(std::iter::successors(Some(first), |timestamp| {
+        Some(timestamp + &congruence.modulus)
+    })
+    .take(count)
+    .collect())
+}
+
+// A bus leaving exactly at the departure time is waited for until its next loop.
+fn calculate_wait_time(departure_time: &u64, loop_duration: &u64) -> u64 {
     loop_duration - departure_time.rem_euclid(*loop_duration)
 }
 
@@ -57,27 +298,106 @@ mod test {
     use super::*;
     use im_rc::vector;
 
+    fn solution(ids: &str) -> Result<BigInt, ScheduleError> {
+        part_two(&vector!(String::from(ids)))
+    }
+
     #[test]
     fn test_part_two() {
-        let input = vector!(String::from("7,11"));
-        assert_eq!(part_two(&input), Some(21));
+        assert_eq!(solution("7,11"), Ok(BigInt::from(21u64)));
+        assert_eq!(solution("17,x,13,19"), Ok(BigInt::from(3417u64)));
+        assert_eq!(solution("67,7,59,61"), Ok(BigInt::from(754018u64)));
+        assert_eq!(solution("67,x,7,59,61"), Ok(BigInt::from(779210u64)));
+        assert_eq!(solution("67,7,x,59,61"), Ok(BigInt::from(1261476u64)));
+        assert_eq!(solution("1789,37,47,1889"), Ok(BigInt::from(1202161486u64)));
+        assert_eq!(
+            solution("7,13,x,x,59,x,31,19"),
+            Ok(BigInt::from(1068781u64))
+        );
+    }
 
-        let input = vector!(String::from("17,x,13,19"));
-        assert_eq!(part_two(&input), Some(3417));
+    #[test]
+    fn test_shared_factors() {
+        assert_eq!(solution("4,x,6"), Ok(BigInt::from(4)));
+        assert_eq!(solution("6,x,4"), Ok(BigInt::from(6)));
+        assert_eq!(
+            solution("4,6"),
+            Err(ScheduleError::NoSolution {
+                bus: Bus { id: 6, offset: 1 },
+                constraints: vector!(Bus { id: 4, offset: 0 })
+            })
+        );
+        assert_eq!(
+            solution("7,0").unwrap_err(),
+            ScheduleError::InvalidBus("0".to_owned())
+        );
 
-        let input = vector!(String::from("67,7,59,61"));
-        assert_eq!(part_two(&input), Some(754018));
+        let large = (0..40).map(|_| "x").join(",") + ",1000000000000000003,1000000000000000009";
+        let timestamp = solution(&large).unwrap();
+        assert_eq!(
+            (&timestamp + 40u32) % 1000000000000000003u64,
+            BigInt::zero()
+        );
+        assert_eq!(
+            (&timestamp + 41u32) % 1000000000000000009u64,
+            BigInt::zero()
+        );
+    }
 
-        let input = vector!(String::from("67,x,7,59,61"));
-        assert_eq!(part_two(&input), Some(779210));
+    #[test]
+    fn test_alignments() {
+        let busses = parse_busses("7,13,x,x,59,x,31,19").unwrap();
+        let subset: Vector<Bus> = busses.into_iter().filter(|bus| bus.id != 59).collect();
+        let timestamps = alignments(&subset, &BigInt::from(1000), 3).unwrap();
+        assert_eq!(timestamps.len(), 3);
+        assert_eq!(
+            &timestamps[1] - &timestamps[0],
+            BigInt::from(7 * 13 * 31 * 19)
+        );
+        assert!(timestamps[0] >= BigInt::from(1000));
+        assert!(subset
+            .iter()
+            .all(|bus| ((&timestamps[0] + bus.offset) % bus.id).is_zero()));
+    }
 
-        let input = vector!(String::from("67,7,x,59,61"));
-        assert_eq!(part_two(&input), Some(1261476));
+    #[test]
+    fn test_schedule() {
+        let schedule = Schedule::parse(&utils::read_strings("input/test.txt").unwrap()).unwrap();
+        assert_eq!(schedule.earliest, 939);
+        assert_eq!(schedule.busses.len(), 5);
+        assert_eq!(
+            schedule.next_bus(939).map(|(bus, wait)| (bus.id, wait)),
+            Some((59, 5))
+        );
+        assert_eq!(
+            schedule.next_bus(944).map(|(bus, wait)| (bus.id, wait)),
+            Some((7, 1))
+        );
+        assert_eq!(
+            schedule
+                .departures(939, 945)
+                .iter()
+                .map(|(timestamp, bus)| (*timestamp, bus.id))
+                .collect::<Vector<_>>(),
+            vector!((944, 59), (945, 7))
+        );
 
-        let input = vector!(String::from("1789,37,47,1889"));
-        assert_eq!(part_two(&input), Some(1202161486));
+        let timetable = schedule.timetable(944, 945);
+        let lines: Vec<&str> = timetable.lines().collect();
+        assert_eq!(
+            lines[0],
+            "time         bus 7   bus 13  bus 59  bus 31  bus 19"
+        );
+        assert_eq!(lines[1], "944            .       .       D       .       .");
+        assert_eq!(lines[2], "945            D       .       .       .       .");
 
-        let input = vector!(String::from("7,13,x,x,59,x,31,19"));
-        assert_eq!(part_two(&input), Some(1068781));
+        assert_eq!(
+            Schedule::parse(&vector!("939".to_owned())),
+            Err(ScheduleError::MissingLine)
+        );
+        assert_eq!(
+            part_one(&utils::read_strings("input/test.txt").unwrap()),
+            Some(295)
+        );
     }
 }
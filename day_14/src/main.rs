@@ -5,78 +5,388 @@ extern crate regex;
 
 use im_rc::vector;
 use im_rc::Vector;
+use itertools::Itertools;
 use pbr::ProgressBar;
 use regex::Regex;
-use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::env;
 use std::iter::FromIterator;
 
 fn main() {
     println!("--- [AoC 2020] Day 14: Docking Data ---");
 
     let input = utils::read_strings_from_param();
-    println!("Solution to part one: {}", part_one(&input).unwrap());
-    println!("Solution to part two: {}", part_two(&input).unwrap());
+    let args: Vector<String> = env::args().collect();
+    let width = args
+        .iter()
+        .skip_while(|arg| *arg != "--width")
+        .nth(1)
+        .map(|width| width.parse().expect("The word width should be a number"));
+
+    let one = part_one(&input, width).unwrap_or_else(|e| panic!("{}", e));
+    let two = part_two(&input, width).unwrap_or_else(|e| panic!("{}", e));
+    println!("Solution to part one: {}", one);
+    println!("Solution to part two: {}", two);
+
+    if let Some(version) = args.iter().skip_while(|arg| *arg != "--dump").nth(1) {
+        let version = match version.as_str() {
+            "1" => Some(Version::One),
+            "2" => Some(Version::Two),
+            _ => None,
+        };
+        match version {
+            Some(version) => {
+                let dump = Computer::run(&input, version, width)
+                    .and_then(|computer| computer.dump())
+                    .unwrap_or_else(|e| panic!("{}", e));
+                print!("{}", to_csv(&dump));
+            }
+            None => println!("Usage: --dump <1|2>, the version of the decoder chip"),
+        }
+    }
 }
 
-fn part_one(input: &Vector<String>) -> Option<u64> {
-    let mut progress = ProgressBar::new(input.len() as u64);
-    let (memory, _) =
-        input.iter().fold(
-            (HashMap::new(), None),
-            |(mut memory, mask), line| match Mask::try_from(&line[..]) {
-                Ok(new_mask) => {
-                    progress.inc();
-                    (memory, Some(new_mask))
-                }
-                _ => {
-                    let instruction = Instruction::try_from(&line[..]).unwrap();
-                    memory.insert(
-                        instruction.address,
-                        mask.map(|m| m.translate(instruction.value))
-                            .unwrap_or(instruction.value),
-                    );
-                    progress.inc();
-                    (memory, mask)
-                }
+fn part_one(input: &Vector<String>, width: Option<u32>) -> Result<u128, ProgramError> {
+    Computer::run(input, Version::One, width).map(|computer| computer.sum())
+}
+
+fn part_two(input: &Vector<String>, width: Option<u32>) -> Result<u128, ProgramError> {
+    Computer::run(input, Version::Two, width).map(|computer| computer.sum())
+}
+
+const DUMP_LIMIT: u128 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProgramError {
+    Syntax {
+        line: usize,
+        text: String,
+    },
+    Width(u32),
+    MaskWidth {
+        line: usize,
+        width: u32,
+        found: u32,
+    },
+    TooWide {
+        line: usize,
+        number: u64,
+        width: u32,
+    },
+    DumpTooLarge(u128),
+}
+
+impl std::fmt::Display for ProgramError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProgramError::Syntax { line, text } => {
+                write!(f, "Line {}: can't parse '{}'", line, text)
+            }
+            ProgramError::Width(width) => {
+                write!(f, "Word width {} should be between 1 and 64", width)
+            }
+            ProgramError::MaskWidth { line, width, found } => write!(
+                f,
+                "Line {}: mask has {} bits but words are {} bits wide",
+                line, found, width
+            ),
+            ProgramError::TooWide {
+                line,
+                number,
+                width,
+            } => write!(
+                f,
+                "Line {}: {} doesn't fit in a {} bit word",
+                line, number, width
+            ),
+            ProgramError::DumpTooLarge(addresses) => write!(
+                f,
+                "Memory holds {} addresses, more than the {} a dump lists",
+                addresses, DUMP_LIMIT
+            ),
+        }
+    }
+}
+
+fn word(width: u32) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+// Without an explicit width the first mask sets it, and until then instructions are unchecked.
+struct Computer {
+    version: Version,
+    width: Option<u32>,
+    mask: Option<Mask>,
+    memory: FloatingMemory,
+}
+
+impl Computer {
+    fn new(version: Version, width: Option<u32>) -> Result<Computer, ProgramError> {
+        match width {
+            Some(width) if width == 0 || width > 64 => Err(ProgramError::Width(width)),
+            _ => Ok(Computer {
+                version,
+                width,
+                mask: None,
+                memory: FloatingMemory::new(),
+            }),
+        }
+    }
+
+    fn run(
+        input: &Vector<String>,
+        version: Version,
+        width: Option<u32>,
+    ) -> Result<Computer, ProgramError> {
+        let mut progress = ProgressBar::new(input.len() as u64);
+        let computer = input.iter().enumerate().try_fold(
+            Computer::new(version, width)?,
+            |mut computer, (index, line)| {
+                progress.inc();
+                computer.execute(index + 1, line)?;
+                Ok(computer)
             },
         );
-    progress.finish();
-    Some(memory.values().sum())
+        progress.finish();
+        computer
+    }
+
+    fn execute(&mut self, line: usize, text: &str) -> Result<(), ProgramError> {
+        if let Ok(mask) = Mask::try_from(text) {
+            if mask.width > 64 {
+                return Err(ProgramError::Width(mask.width));
+            }
+            let width = *self.width.get_or_insert(mask.width);
+            if mask.width != width {
+                return Err(ProgramError::MaskWidth {
+                    line,
+                    width,
+                    found: mask.width,
+                });
+            }
+            self.mask = Some(mask);
+            return Ok(());
+        }
+
+        let instruction = Instruction::try_from(text).map_err(|_| ProgramError::Syntax {
+            line,
+            text: text.to_owned(),
+        })?;
+        if let Some(width) = self.width {
+            if let Some(&number) = [instruction.address, instruction.value]
+                .iter()
+                .find(|&&number| number & !word(width) != 0)
+            {
+                return Err(ProgramError::TooWide {
+                    line,
+                    number,
+                    width,
+                });
+            }
+        }
+
+        let (pattern, value) = match (self.version, self.mask) {
+            (Version::One, Some(mask)) => (
+                AddressPattern::exact(instruction.address),
+                mask.translate(instruction.value),
+            ),
+            (Version::Two, Some(mask)) => {
+                (mask.float_address(instruction.address), instruction.value)
+            }
+            (_, None) => (
+                AddressPattern::exact(instruction.address),
+                instruction.value,
+            ),
+        };
+        self.memory.write(
+            pattern,
+            Write {
+                value,
+                mask: self.mask,
+            },
+        );
+        Ok(())
+    }
+
+    fn sum(&self) -> u128 {
+        self.memory.sum()
+    }
+
+    fn dump(&self) -> Result<Vector<DumpEntry>, ProgramError> {
+        let addresses = self.memory.len();
+        if addresses > DUMP_LIMIT {
+            return Err(ProgramError::DumpTooLarge(addresses));
+        }
+
+        Ok(self
+            .memory
+            .regions
+            .iter()
+            .flat_map(|(pattern, write)| {
+                pattern
+                    .addresses()
+                    .into_iter()
+                    .map(move |address| DumpEntry {
+                        address,
+                        value: write.value,
+                        mask: write.mask,
+                    })
+            })
+            .sorted_by_key(|entry| entry.address)
+            .collect())
+    }
 }
 
-fn part_two(input: &Vector<String>) -> Option<u64> {
-    let mut progress = ProgressBar::new(input.len() as u64);
-    let (memory, _) =
-        input.iter().fold(
-            (HashMap::new(), None),
-            |(mut memory, mask), line| match Mask::try_from(&line[..]) {
-                Ok(new_mask) => {
-                    progress.inc();
-                    (memory, Some(new_mask))
-                }
-                _ => {
-                    let instruction = Instruction::try_from(&line[..]).unwrap();
-                    match mask {
-                        Some(m) => {
-                            let addresses: Vector<_> = m.map_address(instruction.address);
-                            for address in addresses {
-                                memory.insert(address, instruction.value);
-                            }
-                            progress.inc();
-                        }
-                        None => {
-                            memory.insert(instruction.address, instruction.value);
-                            progress.inc();
-                        }
-                    }
-                    (memory, mask)
-                }
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DumpEntry {
+    address: u64,
+    value: u64,
+    mask: Option<Mask>,
+}
+
+fn to_csv(dump: &Vector<DumpEntry>) -> String {
+    std::iter::once("address,value,mask".to_owned())
+        .chain(dump.iter().map(|entry| {
+            format!(
+                "{},{},{}",
+                entry.address,
+                entry.value,
+                entry.mask.map(|mask| mask.bits()).unwrap_or_default()
+            )
+        }))
+        .map(|line| line + "\n")
+        .collect()
+}
+
+// A set of addresses: the bits in `floating` take either value, all other bits equal `fixed`.
+// `fixed` is kept zero on the floating bits so two patterns can be compared directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AddressPattern {
+    fixed: u64,
+    floating: u64,
+}
+
+impl AddressPattern {
+    fn exact(address: u64) -> AddressPattern {
+        AddressPattern {
+            fixed: address,
+            floating: 0,
+        }
+    }
+
+    fn len(&self) -> u128 {
+        1 << self.floating.count_ones()
+    }
+
+    #[cfg(test)]
+    fn contains(&self, address: u64) -> bool {
+        address & !self.floating == self.fixed
+    }
+
+    fn intersects(&self, other: &AddressPattern) -> bool {
+        (self.fixed ^ other.fixed) & !(self.floating | other.floating) == 0
+    }
+
+    // Splits off one pattern per bit that floats here but is fixed in `other`, each taking the value
+    // `other` doesn't have. The pieces are disjoint and together hold every address not in `other`.
+    fn subtract(&self, other: &AddressPattern) -> Vector<AddressPattern> {
+        if !self.intersects(other) {
+            return vector!(*self);
+        }
+
+        let splitting = self.floating & !other.floating;
+        let (pieces, _) = (0..64).filter(|bit| splitting & 1 << bit != 0).fold(
+            (Vector::new(), *self),
+            |(mut pieces, remaining), bit| {
+                let floating = remaining.floating & !(1 << bit);
+                pieces.push_back(AddressPattern {
+                    fixed: remaining.fixed | (!other.fixed & 1 << bit),
+                    floating,
+                });
+                let remaining = AddressPattern {
+                    fixed: remaining.fixed | (other.fixed & 1 << bit),
+                    floating,
+                };
+                (pieces, remaining)
             },
         );
+        pieces
+    }
+
+    fn addresses(&self) -> Vector<u64> {
+        (0..64).filter(|i| self.floating & 1 << i != 0).fold(
+            vector!(self.fixed),
+            |res, floating_index| {
+                res.iter()
+                    .flat_map(|number| vector!(*number, number | 1 << floating_index))
+                    .collect()
+            },
+        )
+    }
+}
 
-    progress.finish();
-    Some(memory.values().sum())
+// Keeps disjoint address patterns with their values; a write carves its addresses out of the older
+// regions, so sums never need to enumerate the addresses behind a pattern.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Write {
+    value: u64,
+    mask: Option<Mask>,
+}
+
+#[derive(Debug, Clone)]
+struct FloatingMemory {
+    regions: Vector<(AddressPattern, Write)>,
+}
+
+impl FloatingMemory {
+    fn new() -> FloatingMemory {
+        FloatingMemory {
+            regions: Vector::new(),
+        }
+    }
+
+    fn write(&mut self, pattern: AddressPattern, value: Write) {
+        self.regions = self
+            .regions
+            .iter()
+            .flat_map(|(region, old)| {
+                region
+                    .subtract(&pattern)
+                    .into_iter()
+                    .map(move |piece| (piece, *old))
+            })
+            .collect();
+        self.regions.push_back((pattern, value));
+    }
+
+    #[cfg(test)]
+    fn read(&self, address: u64) -> Option<u64> {
+        self.regions
+            .iter()
+            .find(|(region, _)| region.contains(address))
+            .map(|(_, write)| write.value)
+    }
+
+    fn sum(&self) -> u128 {
+        self.regions
+            .iter()
+            .map(|(region, write)| region.len() * write.value as u128)
+            .sum()
+    }
+
+    fn len(&self) -> u128 {
+        self.regions.iter().map(|(region, _)| region.len()).sum()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -127,6 +437,7 @@ impl TryFrom<&str> for Instruction {
 struct Mask {
     positive: u64,
     negative: u64,
+    width: u32,
 }
 
 impl Mask {
@@ -140,32 +451,14 @@ impl Mask {
         Mask {
             positive: pos,
             negative: neg,
+            width: value.len() as u32,
         }
     }
 
-    fn translate(&self, value: u64) -> u64 {
-        (value | self.positive) & !self.negative
-    }
-
-    fn map_address(&self, address: u64) -> Vector<u64> {
-        let floating = !(self.positive | self.negative);
-        let with_overrides = address | self.positive;
-
-        (0..36).filter(|i| floating & 1 << i > 0).fold(
-            vector!(with_overrides),
-            |res, floating_index| {
-                res.iter()
-                    .flat_map(|number| vector!(*number, number ^ 1 << floating_index))
-                    .collect()
-            },
-        )
-    }
-}
-
-impl std::fmt::Display for Mask {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let positive_debug = format!("{:036b}", self.positive);
-        let negative_debug = format!("{:036b}", self.negative);
+    fn bits(&self) -> String {
+        let width = self.width as usize;
+        let positive_debug = format!("{:0width$b}", self.positive, width = width);
+        let negative_debug = format!("{:0width$b}", self.negative, width = width);
         let chars = positive_debug
             .chars()
             .zip(negative_debug.chars())
@@ -178,8 +471,30 @@ impl std::fmt::Display for Mask {
                     'X'
                 }
             });
-        let output = String::from_iter(chars);
-        write!(f, "mask = {}", output)
+        String::from_iter(chars)
+    }
+
+    fn translate(&self, value: u64) -> u64 {
+        (value | self.positive) & !self.negative
+    }
+
+    fn float_address(&self, address: u64) -> AddressPattern {
+        let floating = !(self.positive | self.negative) & word(self.width);
+        AddressPattern {
+            fixed: (address | self.positive) & !floating,
+            floating,
+        }
+    }
+
+    #[cfg(test)]
+    fn map_address(&self, address: u64) -> Vector<u64> {
+        self.float_address(address).addresses()
+    }
+}
+
+impl std::fmt::Display for Mask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mask = {}", self.bits())
     }
 }
 
@@ -187,8 +502,10 @@ impl std::fmt::Debug for Mask {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Mask( pos: {:036b} neg: {:036b})",
-            self.positive, self.negative
+            "Mask( pos: {:0width$b} neg: {:0width$b})",
+            self.positive,
+            self.negative,
+            width = self.width as usize
         )
     }
 }
@@ -203,7 +520,7 @@ impl TryFrom<&str> for Mask {
         MASK_RE
             .captures(line)
             .and_then(|res| res.name("mask").map(|r| r.as_str()))
-            .map(|value| Mask::new(value))
+            .map(Mask::new)
             .ok_or(())
     }
 }
@@ -263,4 +580,107 @@ mod test {
         results.sort();
         assert_eq!(results, vector!(26, 27, 58, 59));
     }
+
+    #[test]
+    fn test_floating_memory() {
+        let input = utils::read_strings("input/test2.txt").unwrap();
+        assert_eq!(part_two(&input, None), Ok(208));
+        assert_eq!(
+            part_one(&vector!(format!("mask = {}", "X".repeat(65))), None),
+            Err(ProgramError::Width(65))
+        );
+
+        let wide = Mask::new("XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX0000");
+        let mut memory = FloatingMemory::new();
+        let write = |value| Write { value, mask: None };
+        memory.write(wide.float_address(0), write(3));
+        memory.write(
+            Mask::new("XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX1000").float_address(0),
+            write(5),
+        );
+        memory.write(AddressPattern::exact(16), write(7));
+        assert_eq!(memory.sum(), 3 * ((1 << 32) - 1) + 5 * (1 << 32) + 7);
+        assert_eq!(memory.read(16), Some(7));
+        assert_eq!(memory.read(32), Some(3));
+        assert_eq!(memory.read(8), Some(5));
+        assert_eq!(memory.read(1), None);
+
+        let a = Mask::new("00000000000000000000000000000000XX1X").float_address(0);
+        let b = Mask::new("00000000000000000000000000000000X01X").float_address(0);
+        let pieces = a.subtract(&b);
+        let remaining: Vector<u64> = pieces.iter().flat_map(|p| p.addresses()).sorted().collect();
+        let expected: Vector<u64> = a
+            .addresses()
+            .into_iter()
+            .filter(|address| !b.contains(*address))
+            .sorted()
+            .collect();
+        assert_eq!(remaining, expected);
+    }
+
+    #[test]
+    fn test_word_width() {
+        let input: Vector<String> = vector!("mask = X1X0", "mem[3] = 9", "mem[12] = 1")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let one = Computer::run(&input, Version::One, None).unwrap();
+        assert_eq!(one.sum(), 12 + 4);
+        let two = Computer::run(&input, Version::Two, None).unwrap();
+        assert_eq!(
+            two.dump()
+                .unwrap()
+                .iter()
+                .map(|entry| (entry.address, entry.value))
+                .collect::<Vector<_>>(),
+            vector!(
+                (4, 1),
+                (5, 9),
+                (6, 1),
+                (7, 9),
+                (12, 1),
+                (13, 9),
+                (14, 1),
+                (15, 9)
+            )
+        );
+        assert_eq!(Mask::new("X1X0").to_string(), "mask = X1X0");
+
+        assert_eq!(
+            Computer::run(&input, Version::One, Some(36)).err(),
+            Some(ProgramError::MaskWidth {
+                line: 1,
+                width: 36,
+                found: 4
+            })
+        );
+        let too_wide = input.clone() + vector!("mem[16] = 1".to_owned());
+        assert_eq!(
+            Computer::run(&too_wide, Version::Two, None).err(),
+            Some(ProgramError::TooWide {
+                line: 4,
+                number: 16,
+                width: 4
+            })
+        );
+        assert_eq!(
+            Computer::new(Version::One, Some(65))
+                .err()
+                .map(|e| e.to_string()),
+            Some("Word width 65 should be between 1 and 64".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_dump() {
+        let input = utils::read_strings("input/test.txt").unwrap();
+        let computer = Computer::run(&input, Version::One, None).unwrap();
+        let csv = to_csv(&computer.dump().unwrap());
+        assert_eq!(
+            csv,
+            "address,value,mask\n\
+             7,101,XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X\n\
+             8,64,XXXXXXXXXXXXXXXXXXXXXXXXXXXXX1XXXX0X\n"
+        );
+    }
 }
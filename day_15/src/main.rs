@@ -2,7 +2,9 @@ extern crate im_rc;
 
 use im_rc::vector;
 use im_rc::Vector;
-use std::collections::HashMap;
+use std::cmp;
+use std::env;
+use std::iter;
 
 fn main() {
     println!("--- [AoC 2020] Day 15: Rambunctious Recitation ---");
@@ -11,33 +13,288 @@ fn main() {
     let input = vector!(9, 12, 1, 4, 17, 0, 18);
 
     println!("Solution to part one: {}", part_one(&input));
-    // println!("Solution to part two: {}", part_two(&input).unwrap());
-}
-
-fn part_one(input: &Vector<usize>) -> usize {
-    let memory: HashMap<usize, usize> = input
-        .iter()
-        .enumerate()
-        .filter(|(i, _)| i < &(input.len() - 1))
-        .fold(HashMap::new(), |mut memory, (turn, number)| {
-            memory.insert(*number, turn);
-            memory
-        });
-    let (_, last_number) = ((input.len())..30000000).fold(
-        (memory, *input.last().unwrap()),
-        |(mut memory, last_number), turn| match memory.insert(last_number, turn - 1) {
-            Some(previous_turn) => {
-                let age = turn - previous_turn - 1;
-                // println!( "memory: {:?}", memory );
-                // println!( "turn: {} - last number was {} (last spoken at {}).  Number (age) for this turn: {}", turn, last_number, previous_turn, age );
-                (memory, age)
+    println!("Solution to part two: {}", part_two(&input));
+
+    let args: Vector<String> = env::args().collect();
+    if let Some(turns) = args.iter().skip_while(|arg| *arg != "--stats").nth(1) {
+        let turns = turns
+            .parse()
+            .expect("The number of turns should be a number");
+        let stats = Statistics::gather(&input, turns);
+        if args.contains(&"--csv".to_owned()) {
+            print!("{}", stats.to_csv());
+        } else {
+            print!("{}", stats.to_table());
+        }
+    }
+}
+
+fn part_one(input: &Vector<u32>) -> u32 {
+    nth_number(input, 2020).expect("The game needs starting numbers")
+}
+
+fn part_two(input: &Vector<u32>) -> u32 {
+    nth_number(input, 30_000_000).expect("The game needs starting numbers")
+}
+
+// The number spoken on `turn`, counting from turn 1. There is no turn 0, and nothing is spoken
+// without starting numbers.
+fn nth_number(input: &Vector<u32>, turn: usize) -> Option<u32> {
+    let index = turn.checked_sub(1)?;
+    VanEck::with_capacity(input, turn).nth(index)
+}
+
+// Yields the spoken numbers from turn 1 onwards. `last_seen` is indexed by number and holds the
+// last turn it was spoken, 0 meaning never; no number can exceed the number of turns played.
+struct VanEck {
+    starting: Vector<u32>,
+    last_seen: Vec<u32>,
+    turn: u32,
+    upcoming: u32,
+}
+
+impl VanEck {
+    #[cfg(test)]
+    fn new(starting: &Vector<u32>) -> VanEck {
+        VanEck::with_capacity(starting, 0)
+    }
+
+    // Sizing the memory for the number of turns up front avoids growing it along the way.
+    fn with_capacity(starting: &Vector<u32>, turns: usize) -> VanEck {
+        let largest = starting.iter().max().map_or(0, |&n| n as usize + 1);
+        VanEck {
+            starting: starting.clone(),
+            last_seen: vec![0; cmp::max(turns, largest)],
+            turn: 0,
+            upcoming: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Turn {
+    turn: u32,
+    number: u32,
+    previous: Option<u32>,
+}
+
+impl VanEck {
+    fn turns(&mut self) -> impl Iterator<Item = Turn> + '_ {
+        iter::from_fn(move || self.step())
+    }
+
+    fn step(&mut self) -> Option<Turn> {
+        let spoken = match self.starting.get(self.turn as usize) {
+            Some(&number) => number,
+            None if self.starting.is_empty() => return None,
+            None => self.upcoming,
+        };
+        self.turn += 1;
+
+        if spoken as usize >= self.last_seen.len() {
+            self.last_seen
+                .resize(cmp::max(spoken as usize + 1, self.last_seen.len() * 2), 0);
+        }
+        let previous = self.last_seen[spoken as usize];
+        self.last_seen[spoken as usize] = self.turn;
+        self.upcoming = if previous == 0 {
+            0
+        } else {
+            self.turn - previous
+        };
+
+        Some(Turn {
+            turn: self.turn,
+            number: spoken,
+            previous: if previous == 0 { None } else { Some(previous) },
+        })
+    }
+}
+
+impl Iterator for VanEck {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        self.step().map(|turn| turn.number)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Gap {
+    number: u32,
+    from: u32,
+    to: u32,
+}
+
+type Row = (String, Option<u32>, Option<u32>, Option<u32>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Statistics {
+    turns: u32,
+    distinct: u32,
+    zeros: u32,
+    largest_gap: Option<Gap>,
+    first_repeats: Vector<(u32, Option<u32>)>,
+}
+
+impl Statistics {
+    // Plays `turns` turns once and keeps the running figures; the earliest gap wins a tie.
+    fn gather(starting: &Vector<u32>, turns: usize) -> Statistics {
+        let mut engine = VanEck::with_capacity(starting, turns);
+        let initial = Statistics {
+            turns: 0,
+            distinct: 0,
+            zeros: 0,
+            largest_gap: None,
+            first_repeats: starting.iter().map(|&number| (number, None)).collect(),
+        };
+
+        engine.turns().take(turns).fold(initial, |mut stats, turn| {
+            stats.turns = turn.turn;
+            if turn.number == 0 {
+                stats.zeros += 1;
             }
-            None => {
-                // println!( "memory: {:?}", memory );
-                // println!( "turn: {} - last number was {} (never spoken).  Number for this turn: 0", turn, last_number );
-                (memory, 0)
+            match turn.previous {
+                None => stats.distinct += 1,
+                Some(from) => {
+                    if stats
+                        .largest_gap
+                        .is_none_or(|gap| turn.turn - from > gap.to - gap.from)
+                    {
+                        stats.largest_gap = Some(Gap {
+                            number: turn.number,
+                            from,
+                            to: turn.turn,
+                        });
+                    }
+                    stats
+                        .first_repeats
+                        .iter_mut()
+                        .filter(|(number, repeat)| *number == turn.number && repeat.is_none())
+                        .for_each(|(_, repeat)| *repeat = Some(turn.turn));
+                }
             }
-        },
-    );
-    last_number
+            stats
+        })
+    }
+
+    // Rows of statistic, number, turn and value shared by the table and CSV output.
+    fn rows(&self) -> Vector<Row> {
+        let mut rows = vector!(
+            (
+                "distinct".to_owned(),
+                None,
+                Some(self.turns),
+                Some(self.distinct)
+            ),
+            (
+                "zeros".to_owned(),
+                Some(0),
+                Some(self.turns),
+                Some(self.zeros)
+            )
+        );
+        if let Some(gap) = self.largest_gap {
+            rows.push_back((
+                "largest gap".to_owned(),
+                Some(gap.number),
+                Some(gap.to),
+                Some(gap.to - gap.from),
+            ));
+        }
+        rows.extend(
+            self.first_repeats
+                .iter()
+                .map(|&(number, repeat)| ("first repeat".to_owned(), Some(number), repeat, None)),
+        );
+        rows
+    }
+
+    fn to_csv(&self) -> String {
+        let field = |value: Option<u32>| value.map_or(String::new(), |v| v.to_string());
+        iter::once("statistic,number,turn,value".to_owned())
+            .chain(self.rows().iter().map(|(name, number, turn, value)| {
+                format!(
+                    "{},{},{},{}",
+                    name,
+                    field(*number),
+                    field(*turn),
+                    field(*value)
+                )
+            }))
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    fn to_table(&self) -> String {
+        let field = |value: Option<u32>| value.map_or("-".to_owned(), |v| v.to_string());
+        iter::once(format!(
+            "{:<14} {:>10} {:>10} {:>10}",
+            "statistic", "number", "turn", "value"
+        ))
+        .chain(self.rows().iter().map(|(name, number, turn, value)| {
+            format!(
+                "{:<14} {:>10} {:>10} {:>10}",
+                name,
+                field(*number),
+                field(*turn),
+                field(*value)
+            )
+        }))
+        .map(|line| line + "\n")
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let numbers: Vec<u32> = VanEck::new(&vector!(0, 3, 6)).take(10).collect();
+        assert_eq!(numbers, vec!(0, 3, 6, 0, 3, 3, 1, 0, 4, 0));
+        assert_eq!(VanEck::new(&vector!()).next(), None);
+    }
+
+    #[test]
+    fn test_nth_number() {
+        assert_eq!(part_one(&vector!(0, 3, 6)), 436);
+        assert_eq!(nth_number(&vector!(1, 3, 2), 2020), Some(1));
+        assert_eq!(nth_number(&vector!(3, 1, 2), 2020), Some(1836));
+        assert_eq!(nth_number(&vector!(2, 1, 3), 3), Some(3));
+        assert_eq!(nth_number(&vector!(2, 1, 3), 1), Some(2));
+        assert_eq!(nth_number(&vector!(2, 1, 3), 0), None);
+        assert_eq!(nth_number(&vector!(), 5), None);
+
+        let numbers: Vec<u32> = VanEck::new(&vector!(100, 50)).take(5).collect();
+        assert_eq!(numbers, vec!(100, 50, 0, 0, 1));
+    }
+
+    #[test]
+    fn test_statistics() {
+        let stats = Statistics::gather(&vector!(0, 3, 6), 10);
+        assert_eq!(stats.turns, 10);
+        assert_eq!(stats.distinct, 5);
+        assert_eq!(stats.zeros, 4);
+        assert_eq!(
+            stats.largest_gap,
+            Some(Gap {
+                number: 0,
+                from: 4,
+                to: 8
+            })
+        );
+        assert_eq!(
+            stats.first_repeats,
+            vector!((0, Some(4)), (3, Some(5)), (6, None))
+        );
+
+        let csv = stats.to_csv();
+        assert_eq!(csv.lines().next(), Some("statistic,number,turn,value"));
+        assert!(csv.contains("largest gap,0,8,4\n"));
+        assert!(csv.contains("first repeat,6,,\n"));
+        assert_eq!(stats.to_table().lines().count(), csv.lines().count());
+    }
 }
@@ -1,45 +1,138 @@
 extern crate im_rc;
 
+use im_rc::HashMap;
 use im_rc::Vector;
+use itertools::Itertools;
 use std::convert;
 use std::convert::TryFrom;
+use std::env;
 use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::iter::Peekable;
+use std::str::CharIndices;
 
 fn main() {
     println!("--- [AoC 2020] Day 18: Operation Order ---");
 
+    let precedence = if env::args().any(|arg| arg == "--advanced") {
+        Precedence::AdditionFirst
+    } else {
+        Precedence::Equal
+    };
+
+    if env::args().any(|arg| arg == "--repl") {
+        repl(precedence);
+        return;
+    }
+
     let input = utils::read_strings_from_param();
 
+    if env::args().any(|arg| arg == "--run") {
+        run(&input, precedence);
+        return;
+    }
+
     println!("Solution to part one: {}", part_one(&input));
     println!("Solution to part two: {}", part_two(&input));
+
+    if env::args().any(|arg| arg == "--explain") {
+        explain(&input);
+    }
 }
 
 fn part_one(input: &Vector<String>) -> i64 {
     input
         .iter()
-        .map(|line| Expression::parse(line).unwrap().value())
+        .map(|line| parse_or_panic(line, Expression::parse).value())
         .sum()
 }
 
 fn part_two(input: &Vector<String>) -> i64 {
     input
         .iter()
-        .map(|line| Expression::parse_adv(line).unwrap().value())
+        .map(|line| parse_or_panic(line, Expression::parse_adv).value())
         .sum()
 }
 
-#[derive(Debug)]
+fn explain(input: &Vector<String>) {
+    input.iter().for_each(|line| {
+        let equal = parse_or_panic(line, Expression::parse);
+        let addition_first = parse_or_panic(line, Expression::parse_adv);
+
+        if equal.value() != addition_first.value() {
+            println!("{}", line);
+            println!(
+                "  equal precedence:  {} = {}  [rpn: {}]",
+                equal,
+                equal.value(),
+                equal.to_rpn()
+            );
+            println!(
+                "  addition first:    {} = {}  [rpn: {}]",
+                addition_first,
+                addition_first.value(),
+                addition_first.to_rpn()
+            );
+        }
+    });
+}
+
+fn run(input: &Vector<String>, precedence: Precedence) {
+    let mut calculator = Calculator::new(precedence);
+    input.iter().for_each(|line| {
+        let number = calculator.line_number();
+        match calculator.evaluate_line(line) {
+            Ok(Some(value)) => println!("${} = {}", number, value),
+            Ok(None) => {}
+            Err(error) => println!("${}: {}", number, error.render(line)),
+        }
+    });
+}
+
+fn repl(precedence: Precedence) {
+    let mut calculator = Calculator::new(precedence);
+    let stdin = io::stdin();
+
+    loop {
+        print!("${}> ", calculator.line_number());
+        io::stdout().flush().expect("Failed to flush stdout");
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {
+                let line = line.trim_end();
+                match calculator.evaluate_line(line) {
+                    Ok(Some(value)) => println!("{}", value),
+                    Ok(None) => {}
+                    Err(error) => println!("{}", error.render(line)),
+                }
+            }
+            Err(error) => panic!("Failed to read from stdin: {}", error),
+        }
+    }
+}
+
+fn parse_or_panic(line: &str, parse: fn(&str) -> Result<Expression, ParseError>) -> Expression {
+    parse(line)
+        .unwrap_or_else(|error| panic!("Failed to parse expression:\n{}", error.render(line)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Operand {
     Multiplication,
     Addition,
 }
 
 impl Operand {
-    fn apply(&self, a: &Expression, b: &Expression) -> i64 {
+    fn evaluate(&self, a: i64, b: i64) -> Result<i64, EvaluationError> {
         match self {
-            Self::Multiplication => a.value() * b.value(),
-            Self::Addition => a.value() + b.value(),
+            Self::Multiplication => a.checked_mul(b),
+            Self::Addition => a.checked_add(b),
         }
+        .ok_or_else(|| EvaluationError::Overflow(format!("{} {} {}", a, self, b)))
     }
 }
 
@@ -52,20 +145,23 @@ impl fmt::Display for Operand {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Precedence {
+    Equal,
+    AdditionFirst,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Expression {
     Number(i64),
+    Variable(String),
+    Reference(usize),
     Calculation(Box<Expression>, Operand, Box<Expression>),
 }
 
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Number(i) => write!(f, "{}", i),
-            Self::Calculation(left, operand, right) => {
-                write!(f, "({} {} {})", left, operand, right)
-            }
-        }
+        write!(f, "{}", self.to_parenthesised())
     }
 }
 
@@ -75,187 +171,532 @@ impl convert::From<Expression> for i64 {
     }
 }
 
-#[derive(Debug)]
-enum ParseError {
-    Generic(String),
-    ParenthesisParsing(String),
-    OperatorParsing(String),
-    NumberParsing(String),
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Statement {
+    Let(String, Expression),
+    Evaluate(Expression),
+    Empty,
 }
 
-impl Expression {
-    fn new_calculation(a: Self, operand: Operand, b: Self) -> Self {
-        Self::Calculation(Box::new(a), operand, Box::new(b))
+impl Statement {
+    fn parse(input: &str, precedence: Precedence) -> Result<Self, ParseError> {
+        let tokens = Token::tokenize(input)?;
+        match &tokens[0].kind {
+            TokenKind::End => Ok(Self::Empty),
+            TokenKind::Let => {
+                // A statement cut short points at the end of the input.
+                let end = tokens.last().unwrap().span;
+                let token = |index: usize| {
+                    tokens
+                        .get(index)
+                        .map_or((None, end), |token| (Some(&token.kind), token.span))
+                };
+                match (token(1), token(2)) {
+                    ((Some(TokenKind::Identifier(name)), _), (Some(TokenKind::Assign), _)) => {
+                        Ok(Self::Let(
+                            name.to_owned(),
+                            Expression::parse_tokens(&tokens[3..], precedence)?,
+                        ))
+                    }
+                    ((Some(TokenKind::Identifier(_)), _), (_, span)) => {
+                        Err(ParseError::Generic("Expected '='".to_owned(), span))
+                    }
+                    ((_, span), _) => Err(ParseError::Generic(
+                        "Expected a variable name after 'let'".to_owned(),
+                        span,
+                    )),
+                }
+            }
+            _ => Expression::parse_tokens(&tokens, precedence).map(Self::Evaluate),
+        }
     }
+}
 
-    fn value(&self) -> i64 {
+#[derive(Debug, Clone, Default)]
+struct Environment {
+    variables: HashMap<String, i64>,
+    results: Vector<Option<i64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum EvaluationError {
+    Overflow(String),
+    UndefinedVariable(String),
+    UndefinedReference(usize),
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Number(v) => *v,
-            Self::Calculation(a, operand, b) => operand.apply(a, b),
+            Self::Overflow(calculation) => write!(f, "Overflow while calculating {}", calculation),
+            Self::UndefinedVariable(name) => write!(f, "Undefined variable '{}'", name),
+            Self::UndefinedReference(line) => write!(f, "Line {} has no result", line),
         }
     }
+}
 
-    fn parse(input: &str) -> Result<Self, ParseError> {
-        if let Some((first, remainder)) = Self::take_next(input)? {
-            first.combine_with_remainder(remainder)
-        } else {
-            Ok(Self::Number(0))
+#[derive(Debug, PartialEq, Eq)]
+enum LineError {
+    Parse(ParseError),
+    Evaluation(EvaluationError),
+}
+
+impl LineError {
+    fn render(&self, input: &str) -> String {
+        match self {
+            Self::Parse(error) => error.render(input),
+            Self::Evaluation(error) => error.to_string(),
         }
     }
+}
 
-    fn parse_adv(input: &str) -> Result<Self, ParseError> {
-        if let Some((first, remainder)) = Self::take_next_adv(input)? {
-            first.combine_with_remainder_adv(remainder)
-        } else {
-            Ok(Self::Number(0))
+impl convert::From<ParseError> for LineError {
+    fn from(error: ParseError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl convert::From<EvaluationError> for LineError {
+    fn from(error: EvaluationError) -> Self {
+        Self::Evaluation(error)
+    }
+}
+
+// Evaluates a program line by line; `$n` refers to the result of line n (starting at 1).
+#[derive(Debug, Clone)]
+struct Calculator {
+    precedence: Precedence,
+    environment: Environment,
+}
+
+impl Calculator {
+    fn new(precedence: Precedence) -> Self {
+        Calculator {
+            precedence,
+            environment: Environment::default(),
         }
     }
 
-    fn combine_with_remainder(self, input: &str) -> Result<Self, ParseError> {
-        match Self::take_operand(input)? {
-            Some((operand, remainder)) => match Self::take_next(remainder)? {
-                Some((next, next_input)) => {
-                    let next_combo = Self::new_calculation(self, operand, next);
-                    next_combo.combine_with_remainder(next_input)
-                }
-                None => Err(ParseError::Generic(format!(
-                    "Failed to get next term from '{}'",
-                    remainder
-                ))),
-            },
-            None => Ok(self),
+    fn line_number(&self) -> usize {
+        self.environment.results.len() + 1
+    }
+
+    fn evaluate_line(&mut self, line: &str) -> Result<Option<i64>, LineError> {
+        let result = self.execute(line);
+        self.environment
+            .results
+            .push_back(result.as_ref().ok().copied().flatten());
+        result
+    }
+
+    fn execute(&mut self, line: &str) -> Result<Option<i64>, LineError> {
+        match Statement::parse(line, self.precedence)? {
+            Statement::Let(name, expression) => {
+                let value = expression.evaluate(&self.environment)?;
+                self.environment.variables.insert(name, value);
+                Ok(Some(value))
+            }
+            Statement::Evaluate(expression) => Ok(Some(expression.evaluate(&self.environment)?)),
+            Statement::Empty => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+impl Span {
+    fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    Generic(String, Span),
+    ParenthesisParsing(String, Span),
+    OperatorParsing(String, Span),
+    NumberParsing(String, Span),
+}
+
+impl ParseError {
+    fn message(&self) -> &str {
+        match self {
+            Self::Generic(message, _)
+            | Self::ParenthesisParsing(message, _)
+            | Self::OperatorParsing(message, _)
+            | Self::NumberParsing(message, _) => message,
+        }
+    }
+
+    fn span(&self) -> Span {
+        match self {
+            Self::Generic(_, span)
+            | Self::ParenthesisParsing(_, span)
+            | Self::OperatorParsing(_, span)
+            | Self::NumberParsing(_, span) => *span,
+        }
+    }
+
+    // Renders the line containing the error with the offending part underlined:
+    //   1 + (2 * 3
+    //       ^ Unclosed '('
+    fn render(&self, input: &str) -> String {
+        let span = self.span();
+        let line_start = input[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[span.start..]
+            .find('\n')
+            .map_or(input.len(), |i| span.start + i);
+        let line = &input[line_start..line_end];
+
+        let column = input[line_start..span.start].chars().count();
+        let width = input[span.start..span.end.min(line_end)].chars().count();
+
+        format!(
+            "{}\n{}^{} {}",
+            line,
+            " ".repeat(column),
+            "~".repeat(width.saturating_sub(1)),
+            self.message()
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message(), self.span())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(i64),
+    Identifier(String),
+    Reference(usize),
+    Operand(Operand),
+    Open,
+    Close,
+    Let,
+    Assign,
+    End,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(value) => write!(f, "{}", value),
+            Self::Identifier(name) => write!(f, "{}", name),
+            Self::Reference(line) => write!(f, "${}", line),
+            Self::Operand(operand) => write!(f, "{}", operand),
+            Self::Open => write!(f, "("),
+            Self::Close => write!(f, ")"),
+            Self::Let => write!(f, "let"),
+            Self::Assign => write!(f, "="),
+            Self::End => write!(f, "end of input"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    kind: TokenKind,
+    span: Span,
+}
+
+impl Token {
+    fn new(kind: TokenKind, start: usize, end: usize) -> Self {
+        Token {
+            kind,
+            span: Span::new(start, end),
         }
     }
 
-    fn combine_with_remainder_adv(self, input: &str) -> Result<Self, ParseError> {
-        match Self::take_operand(input)? {
-            Some((Operand::Addition, remainder)) => match Self::take_next_adv(remainder)? {
-                Some((next, next_input)) => {
-                    let next_combo = Self::new_calculation(self, Operand::Addition, next);
-                    next_combo.combine_with_remainder_adv(next_input)
+    fn tokenize(input: &str) -> Result<Vec<Self>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut chars = input.char_indices().peekable();
+
+        while let Some((start, ch)) = chars.next() {
+            match ch {
+                ' ' | '\t' => {}
+                '+' => tokens.push(Self::new(
+                    TokenKind::Operand(Operand::Addition),
+                    start,
+                    start + 1,
+                )),
+                '*' => tokens.push(Self::new(
+                    TokenKind::Operand(Operand::Multiplication),
+                    start,
+                    start + 1,
+                )),
+                '(' => tokens.push(Self::new(TokenKind::Open, start, start + 1)),
+                ')' => tokens.push(Self::new(TokenKind::Close, start, start + 1)),
+                '=' => tokens.push(Self::new(TokenKind::Assign, start, start + 1)),
+                '$' => {
+                    let end = Self::scan_while(&mut chars, start + 1, |c| c.is_ascii_digit());
+                    let line = input[start + 1..end].parse::<usize>().map_err(|_| {
+                        ParseError::NumberParsing(
+                            "Expected a line number after '$'".to_owned(),
+                            Span::new(start, end.max(start + 1)),
+                        )
+                    })?;
+                    tokens.push(Self::new(TokenKind::Reference(line), start, end));
                 }
-                None => Err(ParseError::Generic(format!(
-                    "Failed to get next term from '{}'",
-                    remainder
-                ))),
-            },
-            Some((Operand::Multiplication, remainder)) => match Self::take_next_adv(remainder)? {
-                Some((next, next_input)) => {
-                    let tail = next.combine_with_remainder_adv(next_input)?;
-                    Ok(Self::new_calculation(self, Operand::Multiplication, tail))
+                ch if ch.is_alphabetic() || ch == '_' => {
+                    let end = Self::scan_while(&mut chars, start + ch.len_utf8(), |c| {
+                        c.is_alphanumeric() || c == '_'
+                    });
+                    let kind = match &input[start..end] {
+                        "let" => TokenKind::Let,
+                        name => TokenKind::Identifier(name.to_owned()),
+                    };
+                    tokens.push(Self::new(kind, start, end));
                 }
-                None => Err(ParseError::Generic(format!(
-                    "Failed to get next term from '{}'",
-                    remainder
-                ))),
-            },
-            None => Ok(self),
+                ch if ch.is_ascii_digit() => {
+                    let end = Self::scan_while(&mut chars, start + 1, |c| c.is_ascii_digit());
+                    let value = input[start..end].parse::<i64>().map_err(|_| {
+                        ParseError::NumberParsing(
+                            format!("Number '{}' does not fit in an i64", &input[start..end]),
+                            Span::new(start, end),
+                        )
+                    })?;
+                    tokens.push(Self::new(TokenKind::Number(value), start, end));
+                }
+                ch => {
+                    return Err(ParseError::Generic(
+                        format!("Unexpected character '{}'", ch),
+                        Span::new(start, start + ch.len_utf8()),
+                    ))
+                }
+            }
         }
+
+        tokens.push(Self::new(TokenKind::End, input.len(), input.len()));
+        Ok(tokens)
     }
 
-    fn take_operand(input: &str) -> Result<Option<(Operand, &str)>, ParseError> {
-        match input.chars().next() {
-            Some(' ') => Self::take_operand(&input[1..]),
-            Some('+') => Ok(Some((Operand::Addition, &input[2..]))),
-            Some('*') => Ok(Some((Operand::Multiplication, &input[2..]))),
-            Some(_) => Err(ParseError::OperatorParsing(format!(
-                "Failed to parse {}",
-                input
-            ))),
-            None => Ok(None),
+    // Consumes characters matching the predicate and returns the byte offset just past them.
+    fn scan_while(
+        chars: &mut Peekable<CharIndices>,
+        start: usize,
+        predicate: impl Fn(char) -> bool,
+    ) -> usize {
+        let mut end = start;
+        while let Some((i, c)) = chars.next_if(|(_, c)| predicate(*c)) {
+            end = i + c.len_utf8();
         }
+        end
+    }
+}
+
+type Taken<'t> = Result<(Expression, &'t [Token]), ParseError>;
+
+impl Expression {
+    fn new_calculation(a: Self, operand: Operand, b: Self) -> Self {
+        Self::Calculation(Box::new(a), operand, Box::new(b))
+    }
+
+    fn value(&self) -> i64 {
+        self.evaluate(&Environment::default())
+            .unwrap_or_else(|error| panic!("Failed to evaluate {}: {}", self, error))
+    }
+
+    fn evaluate(&self, environment: &Environment) -> Result<i64, EvaluationError> {
+        self.fold(
+            &|leaf| match leaf {
+                Self::Variable(name) => environment
+                    .variables
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| EvaluationError::UndefinedVariable(name.to_owned())),
+                Self::Reference(line) => line
+                    .checked_sub(1)
+                    .and_then(|index| environment.results.get(index))
+                    .copied()
+                    .flatten()
+                    .ok_or(EvaluationError::UndefinedReference(*line)),
+                Self::Number(value) => Ok(*value),
+                Self::Calculation(..) => unreachable!(),
+            },
+            &|a, operand, b| operand.evaluate(a?, b?),
+        )
+    }
+
+    fn parse(input: &str) -> Result<Self, ParseError> {
+        Self::parse_with(input, Precedence::Equal)
     }
 
-    fn take_next(input: &str) -> Result<Option<(Self, &str)>, ParseError> {
-        match input.chars().next() {
-            None => Ok(None),
-            Some(' ') => Self::take_next(&input[1..]),
-            Some(c) if c.is_ascii_digit() => Self::take_number(input).map(Some),
-            Some('(') => Self::take_parenthesis(input).map(Some),
-            _ => Err(ParseError::Generic(format!("Error parsing '{}'", input))),
+    fn parse_adv(input: &str) -> Result<Self, ParseError> {
+        Self::parse_with(input, Precedence::AdditionFirst)
+    }
+
+    fn parse_with(input: &str, precedence: Precedence) -> Result<Self, ParseError> {
+        let tokens = Token::tokenize(input)?;
+        if tokens.len() == 1 {
+            return Ok(Self::Number(0));
+        }
+
+        Self::parse_tokens(&tokens, precedence)
+    }
+
+    fn parse_tokens(tokens: &[Token], precedence: Precedence) -> Result<Self, ParseError> {
+        let (expression, remainder) = Self::take_expression(tokens, precedence)?;
+        match remainder[0].kind {
+            TokenKind::End => Ok(expression),
+            TokenKind::Close => Err(ParseError::ParenthesisParsing(
+                "Unmatched ')'".to_owned(),
+                remainder[0].span,
+            )),
+            _ => Err(ParseError::OperatorParsing(
+                "Expected '+' or '*'".to_owned(),
+                remainder[0].span,
+            )),
         }
     }
 
-    fn take_next_adv(input: &str) -> Result<Option<(Self, &str)>, ParseError> {
-        match input.chars().next() {
-            None => Ok(None),
-            Some(' ') => Self::take_next(&input[1..]),
-            Some(c) if c.is_ascii_digit() => Self::take_number(input).map(Some),
-            Some('(') => Self::take_parenthesis_adv(input).map(Some),
-            _ => Err(ParseError::Generic(format!("Error parsing '{}'", input))),
+    fn take_expression(tokens: &[Token], precedence: Precedence) -> Taken<'_> {
+        match precedence {
+            Precedence::Equal => Self::take_operations(
+                tokens,
+                precedence,
+                &[Operand::Addition, Operand::Multiplication],
+                Self::take_term,
+            ),
+            Precedence::AdditionFirst => Self::take_operations(
+                tokens,
+                precedence,
+                &[Operand::Multiplication],
+                Self::take_sum,
+            ),
         }
     }
 
-    fn take_parenthesis(expression: &str) -> Result<(Self, &str), ParseError> {
-        let length = expression
-            .chars()
-            .try_fold((-1, 0), |(level, length), ch| match ch {
-                ')' if level == 0 => Err(length),
-                ')' => Ok((level - 1, length + 1)),
-                '(' => Ok((level + 1, length + 1)),
-                _ => Ok((level, length + 1)),
-            });
-
-        match length {
-            Err(length) => {
-                let first = Self::parse(&expression[1..length])?;
-                let tail = &expression[length + 1..];
-                Ok((first, tail))
+    fn take_sum(tokens: &[Token], precedence: Precedence) -> Taken<'_> {
+        Self::take_operations(tokens, precedence, &[Operand::Addition], Self::take_term)
+    }
+
+    fn take_operations<'t>(
+        tokens: &'t [Token],
+        precedence: Precedence,
+        operands: &[Operand],
+        take_next: fn(&[Token], Precedence) -> Taken<'_>,
+    ) -> Taken<'t> {
+        let (mut expression, mut remainder) = take_next(tokens, precedence)?;
+
+        while let TokenKind::Operand(operand) = remainder[0].kind {
+            if !operands.contains(&operand) {
+                break;
             }
-            Ok(_) => Err(ParseError::ParenthesisParsing(format!(
-                "Malformed input '{}'",
-                expression
-            ))),
+            let (next, next_remainder) = take_next(&remainder[1..], precedence)?;
+            expression = Self::new_calculation(expression, operand, next);
+            remainder = next_remainder;
         }
+
+        Ok((expression, remainder))
     }
 
-    fn take_parenthesis_adv(expression: &str) -> Result<(Self, &str), ParseError> {
-        let length = expression
-            .chars()
-            .try_fold((-1, 0), |(level, length), ch| match ch {
-                ')' if level == 0 => Err(length),
-                ')' => Ok((level - 1, length + 1)),
-                '(' => Ok((level + 1, length + 1)),
-                _ => Ok((level, length + 1)),
-            });
-
-        match length {
-            Err(length) => {
-                let first = Self::parse_adv(&expression[1..length])?;
-                let tail = &expression[length + 1..];
-                Ok((first, tail))
+    fn take_term(tokens: &[Token], precedence: Precedence) -> Taken<'_> {
+        let token = &tokens[0];
+        match &token.kind {
+            TokenKind::Number(value) => Ok((Self::Number(*value), &tokens[1..])),
+            TokenKind::Identifier(name) => Ok((Self::Variable(name.to_owned()), &tokens[1..])),
+            TokenKind::Reference(line) => Ok((Self::Reference(*line), &tokens[1..])),
+            TokenKind::Open => {
+                let (inner, remainder) = Self::take_expression(&tokens[1..], precedence)?;
+                match remainder[0].kind {
+                    TokenKind::Close => Ok((inner, &remainder[1..])),
+                    TokenKind::End => Err(ParseError::ParenthesisParsing(
+                        "Unclosed '('".to_owned(),
+                        token.span,
+                    )),
+                    _ => Err(ParseError::OperatorParsing(
+                        "Expected '+', '*' or ')'".to_owned(),
+                        remainder[0].span,
+                    )),
+                }
             }
-            Ok(_) => Err(ParseError::ParenthesisParsing(format!(
-                "Malformed input '{}'",
-                expression
-            ))),
+            TokenKind::Close => Err(ParseError::ParenthesisParsing(
+                "Expected a number or '(' but found ')'".to_owned(),
+                token.span,
+            )),
+            TokenKind::Operand(_) | TokenKind::Let | TokenKind::Assign => Err(ParseError::Generic(
+                format!("Expected a number or '(' but found '{}'", token.kind),
+                token.span,
+            )),
+            TokenKind::End => Err(ParseError::Generic(
+                "Unexpected end of expression".to_owned(),
+                token.span,
+            )),
         }
     }
 
-    fn take_number(expression: &str) -> Result<(Self, &str), ParseError> {
-        let length = expression.chars().try_fold(0, |length, ch| match ch {
-            ch if ch.is_ascii_digit() => Ok(length + 1),
-            _ => Err(length),
-        });
+    // Leaves (numbers, variables and references) are passed to `leaf`.
+    fn fold<T>(
+        &self,
+        leaf: &impl Fn(&Expression) -> T,
+        calculation: &impl Fn(T, &Operand, T) -> T,
+    ) -> T {
+        match self {
+            Self::Calculation(a, operand, b) => {
+                let a = a.fold(leaf, calculation);
+                let b = b.fold(leaf, calculation);
+                calculation(a, operand, b)
+            }
+            leaf_node => leaf(leaf_node),
+        }
+    }
 
-        match length {
-            Err(length) | Ok(length) if length > 0 => {
-                let value = &expression[0..length].parse::<i64>().unwrap(); // TODO deal with error
+    // Post-order walk: operands are visited before the calculation combining them.
+    fn visit(&self, visitor: &mut impl FnMut(&Expression)) {
+        if let Self::Calculation(a, _, b) = self {
+            a.visit(visitor);
+            b.visit(visitor);
+        }
+        visitor(self);
+    }
 
-                let remainder = if expression.len() > length {
-                    &expression[(length + 1)..]
-                } else {
-                    ""
-                };
+    // Calculations that would overflow are left in place so evaluation reports them.
+    #[cfg(test)]
+    fn fold_constants(&self) -> Self {
+        self.fold(&Self::clone, &|a, operand, b| match (a, b) {
+            (Self::Number(a), Self::Number(b)) => operand
+                .evaluate(a, b)
+                .map(Self::Number)
+                .unwrap_or_else(|_| {
+                    Self::new_calculation(Self::Number(a), *operand, Self::Number(b))
+                }),
+            (a, b) => Self::new_calculation(a, *operand, b),
+        })
+    }
 
-                Ok((Self::Number(*value), remainder))
-            }
-            Err(_) | Ok(_) => Err(ParseError::NumberParsing(format!(
-                "Failed to parse digit in {}",
-                expression
-            ))),
+    fn symbol(&self) -> String {
+        match self {
+            Self::Number(value) => value.to_string(),
+            Self::Variable(name) => name.to_owned(),
+            Self::Reference(line) => format!("${}", line),
+            Self::Calculation(_, operand, _) => operand.to_string(),
         }
     }
+
+    fn to_parenthesised(&self) -> String {
+        self.fold(&Self::symbol, &|a, operand, b| {
+            format!("({} {} {})", a, operand, b)
+        })
+    }
+
+    fn to_rpn(&self) -> String {
+        let mut symbols = Vec::new();
+        self.visit(&mut |node| symbols.push(node.symbol()));
+        symbols.iter().join(" ")
+    }
 }
 
 impl TryFrom<&str> for Expression {
@@ -359,4 +800,133 @@ mod test {
             23340
         );
     }
+
+    #[test]
+    fn test_error_spans() {
+        assert_eq!(
+            Expression::parse("1 + (2 * 3").unwrap_err(),
+            ParseError::ParenthesisParsing("Unclosed '('".to_owned(), Span::new(4, 5))
+        );
+        assert_eq!(
+            Expression::parse("1 + 2)").unwrap_err(),
+            ParseError::ParenthesisParsing("Unmatched ')'".to_owned(), Span::new(5, 6))
+        );
+        assert_eq!(
+            Expression::parse("1 + 2 3").unwrap_err(),
+            ParseError::OperatorParsing("Expected '+' or '*'".to_owned(), Span::new(6, 7))
+        );
+        assert_eq!(
+            Expression::parse("1 - 2").unwrap_err(),
+            ParseError::Generic("Unexpected character '-'".to_owned(), Span::new(2, 3))
+        );
+        assert_eq!(
+            Expression::parse("1 + 99999999999999999999")
+                .unwrap_err()
+                .span(),
+            Span::new(4, 24)
+        );
+        assert_eq!(
+            Expression::parse("1 + ").unwrap_err().span(),
+            Span::new(4, 4)
+        );
+        assert_eq!(
+            Statement::parse("let", Precedence::Equal).unwrap_err(),
+            ParseError::Generic(
+                "Expected a variable name after 'let'".to_owned(),
+                Span::new(3, 3)
+            )
+        );
+        assert_eq!(
+            Statement::parse("let x", Precedence::Equal).unwrap_err(),
+            ParseError::Generic("Expected '='".to_owned(), Span::new(5, 5))
+        );
+        assert_eq!(
+            Statement::parse("let 3 = 4", Precedence::Equal).unwrap_err(),
+            ParseError::Generic(
+                "Expected a variable name after 'let'".to_owned(),
+                Span::new(4, 5)
+            )
+        );
+    }
+
+    #[test]
+    fn test_error_rendering() {
+        let input = "2 * (3 + 44 4)";
+        assert_eq!(
+            Expression::parse(input).unwrap_err().render(input),
+            "2 * (3 + 44 4)\n            ^ Expected '+', '*' or ')'"
+        );
+        let input = "1 + 99999999999999999999";
+        assert_eq!(
+            Expression::parse(input).unwrap_err().render(input),
+            "1 + 99999999999999999999\n    ^~~~~~~~~~~~~~~~~~~~ Number '99999999999999999999' does not fit in an i64"
+        );
+    }
+
+    #[test]
+    fn test_ast_conversions() {
+        let equal = Expression::parse("1 + 2 * 3 + 4").unwrap();
+        let addition_first = Expression::parse_adv("1 + 2 * 3 + 4").unwrap();
+
+        assert_eq!(equal.to_parenthesised(), "(((1 + 2) * 3) + 4)");
+        assert_eq!(addition_first.to_parenthesised(), "((1 + 2) * (3 + 4))");
+        assert_eq!(equal.to_rpn(), "1 2 + 3 * 4 +");
+        assert_eq!(addition_first.to_rpn(), "1 2 + 3 4 + *");
+
+        assert_eq!(equal.fold_constants(), Expression::Number(13));
+        assert_eq!(addition_first.fold_constants(), Expression::Number(21));
+        assert_eq!(equal.fold(&|_| 1, &|a, _, b| a + b), 4);
+    }
+
+    #[test]
+    fn test_calculator() {
+        let mut calculator = Calculator::new(Precedence::AdditionFirst);
+        assert_eq!(calculator.evaluate_line("let x = 2 * 3 + 4"), Ok(Some(14)));
+        assert_eq!(calculator.evaluate_line(""), Ok(None));
+        assert_eq!(calculator.evaluate_line("x + 1 * $1"), Ok(Some(210)));
+        assert_eq!(calculator.evaluate_line("let x = x * 2"), Ok(Some(28)));
+        assert_eq!(calculator.evaluate_line("$3 + x"), Ok(Some(238)));
+        assert_eq!(calculator.line_number(), 6);
+
+        assert_eq!(
+            calculator.evaluate_line("y + 1"),
+            Err(LineError::Evaluation(EvaluationError::UndefinedVariable(
+                "y".to_owned()
+            )))
+        );
+        assert_eq!(
+            calculator.evaluate_line("$2 + $6 + $7"),
+            Err(LineError::Evaluation(EvaluationError::UndefinedReference(
+                2
+            )))
+        );
+        assert_eq!(
+            calculator.evaluate_line("let = 3"),
+            Err(LineError::Parse(ParseError::Generic(
+                "Expected a variable name after 'let'".to_owned(),
+                Span::new(4, 5)
+            )))
+        );
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let mut calculator = Calculator::new(Precedence::Equal);
+        assert_eq!(
+            calculator.evaluate_line("9223372036854775807 + 1"),
+            Err(LineError::Evaluation(EvaluationError::Overflow(
+                "9223372036854775807 + 1".to_owned()
+            )))
+        );
+
+        let overflowing = Expression::parse("x + (4611686018427387904 * 2)").unwrap();
+        assert_eq!(overflowing.fold_constants(), overflowing);
+        assert_eq!(
+            Expression::parse("x + 2 * (3 + 4)")
+                .unwrap()
+                .fold_constants()
+                .to_parenthesised(),
+            "((x + 2) * 7)"
+        );
+    }
 }
@@ -6,6 +6,8 @@ use im_rc::HashMap;
 use im_rc::HashSet;
 use im_rc::Vector;
 use itertools::Itertools;
+use std::env;
+use std::fmt;
 
 fn main() {
     println!("--- [AoC 2020] Day 19: Monster Messages ---");
@@ -14,139 +16,933 @@ fn main() {
 
     println!("Solution to part one: {}", part_one(&input));
     println!("Solution to part two: {}", part_two(&input));
+
+    let args: Vector<String> = env::args().collect();
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+
+    if flag("--trees") {
+        let grammar = Grammar::compile(&part_two_rules(&input), 0);
+        messages(&input)
+            .filter_map(|message| grammar.parse(message).map(|tree| (message, tree)))
+            .for_each(|(message, tree)| println!("{}\n{}", message, tree));
+    }
+
+    let grammar = if flag("--patched") {
+        Grammar::compile(&part_two_rules(&input), 0)
+    } else {
+        Grammar::compile(&rules(&input), 0)
+    };
+
+    if flag("--check") {
+        let diagnostics = grammar.diagnostics();
+        if diagnostics.is_empty() {
+            println!("No problems found in the grammar");
+        }
+        diagnostics
+            .iter()
+            .for_each(|diagnostic| println!("{}", diagnostic));
+    }
+
+    if flag("--ebnf") {
+        print!("{}", grammar.to_ebnf());
+    }
+
+    if flag("--regex") {
+        match grammar.to_regex() {
+            Ok(regex) => println!("{}", regex),
+            Err(error) => println!("{}", error),
+        }
+    }
+
+    if let Some(rule) = args
+        .iter()
+        .skip_while(|arg| *arg != "--enumerate")
+        .nth(1)
+        .map(|rule| rule.parse::<i32>().expect("Expected a rule number"))
+    {
+        match grammar.enumerate(rule, LANGUAGE_LIMIT) {
+            Ok(strings) => strings.iter().for_each(|string| println!("{}", string)),
+            Err(error) => println!("{}", error),
+        }
+    }
 }
 
 fn part_one(input: &Vector<String>) -> usize {
-    let rules = Rule::parse_all(input.iter().take_while(|line| !line.is_empty()).collect());
-    let rule_zero = rules.get(&0).unwrap();
-    input
-        .iter()
-        .skip_while(|line| !line.is_empty())
-        .filter(|line| !line.is_empty())
-        .filter(|line| {
-            rule_zero
-                .matches(line, &rules)
-                .map(|res| res.iter().any(|s| s.is_empty()))
-                .unwrap_or(false)
-        })
+    let grammar = Grammar::compile(&rules(input), 0);
+    messages(input)
+        .filter(|message| grammar.matches(message))
         .count()
 }
 
 fn part_two(input: &Vector<String>) -> usize {
-    let rules = Rule::parse_all(input.iter().take_while(|line| !line.is_empty()).collect());
+    let grammar = Grammar::compile(&part_two_rules(input), 0);
+    messages(input)
+        .filter(|message| grammar.matches(message))
+        .count()
+}
+
+fn rules(input: &Vector<String>) -> HashMap<i32, Rule> {
+    Rule::parse_all(input.iter().take_while(|line| !line.is_empty()).collect())
+        .unwrap_or_else(|error| panic!("{}", error))
+}
 
+fn part_two_rules(input: &Vector<String>) -> HashMap<i32, Rule> {
     let part_two_changes = vector!("8: 42 | 42 8", "11: 42 31 | 42 11 31");
-    let rules = part_two_changes.iter().fold(rules, |rs, line| {
-        let (nb, r) = Rule::parse(line);
+    part_two_changes.iter().fold(rules(input), |rs, line| {
+        let (nb, r) = Rule::parse(line).unwrap();
         rs.update(nb, r)
-    });
-    let rule_zero = rules.get(&0).unwrap();
+    })
+}
+
+fn messages(input: &Vector<String>) -> impl Iterator<Item = &String> {
     input
         .iter()
         .skip_while(|line| !line.is_empty())
         .filter(|line| !line.is_empty())
-        .filter(|line| {
-            rule_zero
-                .matches(line, &rules)
-                .map(|res| res.iter().any(|s| s.is_empty()))
-                .unwrap_or(false)
-        })
-        .count()
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum Rule {
     Character(char),
     Sequence(Vector<i32>),
     Either(Vector<Rule>),
 }
 
-impl Rule {
-    fn matches<'a>(
-        &self,
-        pattern: &'a str,
-        rules: &HashMap<i32, Rule>,
-    ) -> Result<HashSet<&'a str>, ()> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseError {
+    MissingColon(String),
+    RuleNumber(String),
+    UnsupportedRule(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Character(ch) if pattern.chars().next().map(|c| &c == ch).unwrap_or(false) => {
-                Ok(hashset!(&pattern[1..]))
+            Self::MissingColon(line) => write!(f, "Expected '<number>: <rule>' in '{}'", line),
+            Self::RuleNumber(number) => write!(f, "Invalid rule number '{}'", number),
+            Self::UnsupportedRule(rule) => write!(f, "Unsupported rule '{}'", rule),
+        }
+    }
+}
+
+impl Rule {
+    fn parse_all(input: Vector<&String>) -> Result<HashMap<i32, Rule>, ParseError> {
+        input.iter().map(|line| Self::parse(&line[..])).collect()
+    }
+
+    fn parse(input: &str) -> Result<(i32, Rule), ParseError> {
+        let (number_part, rule_part) = input
+            .split(':')
+            .collect_tuple()
+            .ok_or_else(|| ParseError::MissingColon(input.to_owned()))?;
+        let number = number_part
+            .trim()
+            .parse::<i32>()
+            .map_err(|_| ParseError::RuleNumber(number_part.to_owned()))?;
+        let rule = Self::parse_rule(rule_part.trim())?;
+        Ok((number, rule))
+    }
+
+    fn parse_rule(input: &str) -> Result<Rule, ParseError> {
+        let pieces: Vector<_> = input.split('|').map(|piece| piece.trim()).collect();
+        if pieces.len() == 1 {
+            let unsupported = || ParseError::UnsupportedRule(input.to_owned());
+            let piece = pieces.head().unwrap();
+            match piece.chars().next() {
+                Some('"') => match piece.chars().collect_tuple() {
+                    Some(('"', ch, '"')) => Ok(Rule::Character(ch)),
+                    _ => Err(unsupported()),
+                },
+                Some(ch) if ch.is_ascii_digit() => piece
+                    .split_whitespace()
+                    .map(|i| i.parse::<i32>().map_err(|_| unsupported()))
+                    .collect::<Result<_, _>>()
+                    .map(Rule::Sequence),
+                _ => Err(unsupported()),
             }
-            Self::Character(_) => Err(()),
-            Self::Either(options) => {
-                let next_tails = options
+        } else {
+            pieces
+                .iter()
+                .map(|piece| Self::parse_rule(piece))
+                .collect::<Result<_, _>>()
+                .map(Rule::Either)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Symbol {
+    Terminal(char),
+    NonTerminal(i32),
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Terminal('"') => write!(f, "'\"'"),
+            Self::Terminal(ch) => write!(f, "\"{}\"", ch),
+            Self::NonTerminal(rule) => write!(f, "r{}", rule),
+        }
+    }
+}
+
+// An Earley item: alternative `alternative` of `rule`, matched up to `dot`, started at `origin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Item {
+    rule: i32,
+    alternative: usize,
+    dot: usize,
+    origin: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseTree {
+    Character(char),
+    Node {
+        rule: i32,
+        alternative: usize,
+        children: Vector<ParseTree>,
+    },
+}
+
+impl ParseTree {
+    fn fmt_indented(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        match self {
+            Self::Character(ch) => writeln!(f, "{}\"{}\"", "  ".repeat(depth), ch),
+            Self::Node {
+                rule,
+                alternative,
+                children,
+            } => {
+                writeln!(f, "{}{} [{}]", "  ".repeat(depth), rule, alternative)?;
+                children
                     .iter()
-                    .filter_map(|option| match option.matches(pattern, rules) {
-                        Ok(tails) => Some(tails),
-                        Err(()) => None,
+                    .try_for_each(|child| child.fmt_indented(f, depth + 1))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ParseTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indented(f, 0)
+    }
+}
+
+// Context-free grammar compiled from a rule set, recognised with an Earley parser so
+// arbitrary (including left) recursion is supported.
+#[derive(Debug, Clone)]
+struct Grammar {
+    start: i32,
+    productions: HashMap<i32, Vector<Vector<Symbol>>>,
+}
+
+impl Grammar {
+    fn compile(rules: &HashMap<i32, Rule>, start: i32) -> Self {
+        let productions = rules
+            .iter()
+            .map(|(number, rule)| (*number, Self::alternatives(rule)))
+            .collect();
+        Grammar { start, productions }
+    }
+
+    fn alternatives(rule: &Rule) -> Vector<Vector<Symbol>> {
+        match rule {
+            Rule::Character(ch) => vector!(vector!(Symbol::Terminal(*ch))),
+            Rule::Sequence(sequence) => {
+                vector!(sequence.iter().map(|nb| Symbol::NonTerminal(*nb)).collect())
+            }
+            Rule::Either(options) => options.iter().flat_map(Self::alternatives).collect(),
+        }
+    }
+
+    fn symbols(&self, rule: i32, alternative: usize) -> &Vector<Symbol> {
+        &self.productions[&rule][alternative]
+    }
+
+    fn predictions(&self, rule: i32, origin: usize) -> Vector<Item> {
+        self.productions
+            .get(&rule)
+            .map(|alternatives| {
+                (0..alternatives.len())
+                    .map(|alternative| Item {
+                        rule,
+                        alternative,
+                        dot: 0,
+                        origin,
                     })
-                    .fold(HashSet::new(), |a, b| a.union(b));
-                if next_tails.is_empty() {
-                    Err(())
-                } else {
-                    Ok(next_tails)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Returns every completed (rule, alternative, start, end) span found while parsing.
+    fn completions(&self, message: &[char]) -> HashSet<(i32, usize, usize, usize)> {
+        let mut chart: Vector<HashSet<Item>> =
+            (0..=message.len()).map(|_| HashSet::new()).collect();
+        let mut completed = HashSet::new();
+        chart[0] = self.predictions(self.start, 0).into_iter().collect();
+
+        for position in 0..=message.len() {
+            let mut pending: Vector<Item> = chart[position].iter().copied().collect();
+
+            while let Some(item) = pending.pop_front() {
+                let next_items: Vector<Item> =
+                    match self.symbols(item.rule, item.alternative).get(item.dot) {
+                        None => {
+                            completed.insert((item.rule, item.alternative, item.origin, position));
+                            chart[item.origin]
+                                .iter()
+                                .filter(|waiting| {
+                                    self.symbols(waiting.rule, waiting.alternative)
+                                        .get(waiting.dot)
+                                        == Some(&Symbol::NonTerminal(item.rule))
+                                })
+                                .map(|waiting| Item {
+                                    dot: waiting.dot + 1,
+                                    ..*waiting
+                                })
+                                .collect()
+                        }
+                        Some(Symbol::NonTerminal(rule)) => self.predictions(*rule, position),
+                        Some(Symbol::Terminal(ch)) => {
+                            if message.get(position) == Some(ch) {
+                                chart[position + 1].insert(Item {
+                                    dot: item.dot + 1,
+                                    ..item
+                                });
+                            }
+                            Vector::new()
+                        }
+                    };
+
+                next_items.into_iter().for_each(|next| {
+                    if chart[position].insert(next).is_none() {
+                        pending.push_back(next);
+                    }
+                });
+            }
+        }
+
+        completed
+    }
+
+    fn matches(&self, message: &str) -> bool {
+        let message: Vec<char> = message.chars().collect();
+        self.completions(&message)
+            .iter()
+            .any(|&(rule, _, start, end)| rule == self.start && start == 0 && end == message.len())
+    }
+
+    fn parse(&self, message: &str) -> Option<ParseTree> {
+        let message: Vec<char> = message.chars().collect();
+        let completed = self.completions(&message);
+        TreeBuilder {
+            grammar: self,
+            message: &message,
+            completed: &completed,
+        }
+        .node(self.start, 0, message.len(), &HashSet::new())
+    }
+}
+
+struct TreeBuilder<'a> {
+    grammar: &'a Grammar,
+    message: &'a [char],
+    completed: &'a HashSet<(i32, usize, usize, usize)>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    // `visiting` guards against unit cycles (e.g. `1: 2`, `2: 1`) spanning the same range.
+    fn node(
+        &self,
+        rule: i32,
+        start: usize,
+        end: usize,
+        visiting: &HashSet<(i32, usize, usize)>,
+    ) -> Option<ParseTree> {
+        if visiting.contains(&(rule, start, end)) {
+            return None;
+        }
+        let visiting = visiting.update((rule, start, end));
+
+        let alternatives = self.grammar.productions.get(&rule)?.len();
+        (0..alternatives)
+            .filter(|alternative| self.completed.contains(&(rule, *alternative, start, end)))
+            .find_map(|alternative| {
+                self.children(
+                    self.grammar.symbols(rule, alternative),
+                    start,
+                    end,
+                    &visiting,
+                )
+                .map(|children| ParseTree::Node {
+                    rule,
+                    alternative,
+                    children,
+                })
+            })
+    }
+
+    fn children(
+        &self,
+        symbols: &Vector<Symbol>,
+        start: usize,
+        end: usize,
+        visiting: &HashSet<(i32, usize, usize)>,
+    ) -> Option<Vector<ParseTree>> {
+        match symbols.head() {
+            None if start == end => Some(Vector::new()),
+            None => None,
+            Some(Symbol::Terminal(ch)) if self.message.get(start) == Some(ch) => {
+                let mut children = self.children(&symbols.skip(1), start + 1, end, visiting)?;
+                children.push_front(ParseTree::Character(*ch));
+                Some(children)
+            }
+            Some(Symbol::Terminal(_)) => None,
+            Some(Symbol::NonTerminal(rule)) => ((start + 1)..=end).find_map(|middle| {
+                let mut children = self.children(&symbols.skip(1), middle, end, visiting)?;
+                children.push_front(self.node(*rule, start, middle, visiting)?);
+                Some(children)
+            }),
+        }
+    }
+}
+
+// Upper bound on the number of strings computed for a single rule's language.
+const LANGUAGE_LIMIT: usize = 100_000;
+
+type Language = Result<HashSet<String>, GrammarError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Diagnostic {
+    MissingStart(i32),
+    UndefinedRule {
+        rule: i32,
+        reference: i32,
+    },
+    Unreachable(i32),
+    LeftRecursion(Vector<i32>),
+    AmbiguousAlternatives {
+        rule: i32,
+        first: usize,
+        second: usize,
+    },
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingStart(rule) => write!(f, "Start rule {} is not defined", rule),
+            Self::UndefinedRule { rule, reference } => {
+                write!(f, "Rule {} refers to undefined rule {}", rule, reference)
+            }
+            Self::Unreachable(rule) => write!(f, "Rule {} is unreachable", rule),
+            Self::LeftRecursion(path) => write!(
+                f,
+                "Rule {} is left-recursive: {}",
+                path.head().unwrap(),
+                path.iter().join(" -> ")
+            ),
+            Self::AmbiguousAlternatives {
+                rule,
+                first,
+                second,
+            } => write!(
+                f,
+                "Rule {} has overlapping alternatives {} and {}",
+                rule, first, second
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GrammarError {
+    UndefinedRule(i32),
+    Recursive(i32),
+    TooLarge(i32),
+    UnsupportedRecursion(i32),
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UndefinedRule(rule) => write!(f, "Rule {} is not defined", rule),
+            Self::Recursive(rule) => {
+                write!(f, "Rule {} is recursive, its language is infinite", rule)
+            }
+            Self::TooLarge(rule) => write!(
+                f,
+                "Rule {} produces more than {} strings",
+                rule, LANGUAGE_LIMIT
+            ),
+            Self::UnsupportedRecursion(rule) => write!(
+                f,
+                "Rule {} uses recursion not supported by the regex export",
+                rule
+            ),
+        }
+    }
+}
+
+impl Grammar {
+    fn sorted_rules(&self) -> Vector<i32> {
+        self.productions.keys().copied().sorted().collect()
+    }
+
+    fn references(&self, rule: i32) -> impl Iterator<Item = i32> + '_ {
+        self.productions
+            .get(&rule)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|symbol| match symbol {
+                Symbol::NonTerminal(reference) => Some(*reference),
+                Symbol::Terminal(_) => None,
+            })
+    }
+
+    fn left_corners(&self, rule: i32) -> impl Iterator<Item = i32> + '_ {
+        self.productions
+            .get(&rule)
+            .into_iter()
+            .flatten()
+            .filter_map(|symbols| match symbols.head() {
+                Some(Symbol::NonTerminal(reference)) => Some(*reference),
+                _ => None,
+            })
+    }
+
+    fn diagnostics(&self) -> Vector<Diagnostic> {
+        let rules = self.sorted_rules();
+        let reachable = self.reachable();
+        let mut languages = HashMap::new();
+
+        let missing_start = Some(Diagnostic::MissingStart(self.start))
+            .filter(|_| !self.productions.contains_key(&self.start));
+
+        let undefined = rules.iter().flat_map(|&rule| {
+            self.references(rule)
+                .unique()
+                .filter(|reference| !self.productions.contains_key(reference))
+                .map(move |reference| Diagnostic::UndefinedRule { rule, reference })
+        });
+
+        let unreachable = rules
+            .iter()
+            .filter(|rule| !reachable.contains(rule))
+            .map(|rule| Diagnostic::Unreachable(*rule));
+
+        let left_recursive = rules
+            .iter()
+            .filter_map(|rule| self.left_recursion(*rule))
+            .map(Diagnostic::LeftRecursion);
+
+        let ambiguous: Vector<_> = rules
+            .iter()
+            .flat_map(|&rule| {
+                (0..self.productions[&rule].len())
+                    .tuple_combinations()
+                    .map(move |(first, second)| (rule, first, second))
+            })
+            .filter(|&(rule, first, second)| self.overlapping(rule, first, second, &mut languages))
+            .map(|(rule, first, second)| Diagnostic::AmbiguousAlternatives {
+                rule,
+                first,
+                second,
+            })
+            .collect();
+
+        missing_start
+            .into_iter()
+            .chain(undefined)
+            .chain(unreachable)
+            .chain(left_recursive)
+            .chain(ambiguous)
+            .collect()
+    }
+
+    fn reachable(&self) -> HashSet<i32> {
+        let mut reachable = HashSet::new();
+        let mut pending = vector!(self.start);
+        while let Some(rule) = pending.pop_back() {
+            if reachable.insert(rule).is_none() {
+                pending.extend(self.references(rule));
+            }
+        }
+        reachable
+    }
+
+    // Returns the shortest chain of leftmost references leading from `rule` back to itself.
+    fn left_recursion(&self, rule: i32) -> Option<Vector<i32>> {
+        let mut parents: HashMap<i32, i32> = HashMap::new();
+        let mut pending = vector!(rule);
+
+        while let Some(current) = pending.pop_front() {
+            for next in self.left_corners(current) {
+                if next == rule {
+                    let mut path = vector!(rule);
+                    let mut node = current;
+                    while node != rule {
+                        path.push_front(node);
+                        node = parents[&node];
+                    }
+                    path.push_front(rule);
+                    return Some(path);
+                }
+                if !parents.contains_key(&next) {
+                    parents.insert(next, current);
+                    pending.push_back(next);
                 }
             }
-            Self::Sequence(sequence) => {
-                let seq_rules: Vector<_> =
-                    sequence.iter().map(|nb| rules.get(nb).unwrap()).collect();
-                let outcomes = seq_rules
+        }
+
+        None
+    }
+
+    // Two alternatives overlap when they are identical or when both have a finite language
+    // and share a string; alternatives with an infinite language are not compared.
+    fn overlapping(
+        &self,
+        rule: i32,
+        first: usize,
+        second: usize,
+        languages: &mut HashMap<i32, Language>,
+    ) -> bool {
+        let first = self.symbols(rule, first);
+        let second = self.symbols(rule, second);
+        if first == second {
+            return true;
+        }
+
+        let visiting = HashSet::new();
+        match (
+            self.sequence_language(rule, first, LANGUAGE_LIMIT, languages, &visiting),
+            self.sequence_language(rule, second, LANGUAGE_LIMIT, languages, &visiting),
+        ) {
+            (Ok(first), Ok(second)) => !first.intersection(second).is_empty(),
+            _ => false,
+        }
+    }
+
+    fn enumerate(&self, rule: i32, limit: usize) -> Result<Vector<String>, GrammarError> {
+        self.language(rule, limit, &mut HashMap::new(), &HashSet::new())
+            .map(|language| language.into_iter().sorted().collect())
+    }
+
+    fn language(
+        &self,
+        rule: i32,
+        limit: usize,
+        languages: &mut HashMap<i32, Language>,
+        visiting: &HashSet<i32>,
+    ) -> Language {
+        if let Some(language) = languages.get(&rule) {
+            return language.clone();
+        }
+        if visiting.contains(&rule) {
+            return Err(GrammarError::Recursive(rule));
+        }
+
+        let alternatives = self
+            .productions
+            .get(&rule)
+            .ok_or(GrammarError::UndefinedRule(rule))?;
+        let visiting = visiting.update(rule);
+
+        let language = alternatives
+            .iter()
+            .try_fold(HashSet::new(), |language, symbols| {
+                let strings = self.sequence_language(rule, symbols, limit, languages, &visiting)?;
+                Some(language.union(strings))
+                    .filter(|language| language.len() <= limit)
+                    .ok_or(GrammarError::TooLarge(rule))
+            });
+
+        languages.insert(rule, language.clone());
+        language
+    }
+
+    fn sequence_language(
+        &self,
+        rule: i32,
+        symbols: &Vector<Symbol>,
+        limit: usize,
+        languages: &mut HashMap<i32, Language>,
+        visiting: &HashSet<i32>,
+    ) -> Language {
+        symbols
+            .iter()
+            .try_fold(hashset!(String::new()), |prefixes, symbol| {
+                let strings = match symbol {
+                    Symbol::Terminal(ch) => hashset!(ch.to_string()),
+                    Symbol::NonTerminal(reference) => {
+                        self.language(*reference, limit, languages, visiting)?
+                    }
+                };
+                if prefixes.len() * strings.len() > limit {
+                    return Err(GrammarError::TooLarge(rule));
+                }
+                Ok(prefixes
                     .iter()
-                    .try_fold(hashset!(pattern), |inputs, rule| {
-                        let outputs: HashSet<_> = inputs
+                    .flat_map(|prefix| {
+                        strings
                             .iter()
-                            .filter_map(|input| match rule.matches(input, rules) {
-                                Ok(outs) => Some(outs),
-                                Err(_) => None,
-                            })
-                            .flatten()
-                            .collect();
-
-                        if outputs.is_empty() {
-                            Err(())
-                        } else {
-                            Ok(outputs)
-                        }
-                    });
+                            .map(move |string| format!("{}{}", prefix, string))
+                    })
+                    .collect())
+            })
+    }
 
-                match outcomes {
-                    Err(()) => Err(()),
-                    Ok(res) if res.is_empty() => Err(()),
-                    Ok(res) => Ok(res),
+    fn to_ebnf(&self) -> String {
+        self.sorted_rules()
+            .iter()
+            .map(|rule| {
+                let alternatives = self.productions[rule]
+                    .iter()
+                    .map(|symbols| symbols.iter().join(" , "))
+                    .join(" | ");
+                format!("r{} = {} ;\n", rule, alternatives)
+            })
+            .collect()
+    }
+
+    // Only direct recursion at the start or end of an alternative (`8: 42 | 42 8`) is
+    // exported. Other recursion is rejected, even where the language is regular (e.g. mutual
+    // recursion through another rule).
+    fn to_regex(&self) -> Result<String, GrammarError> {
+        self.rule_regex(self.start, &HashSet::new(), &mut HashMap::new())
+            .map(|regex| format!("^{}$", regex))
+    }
+
+    fn rule_regex(
+        &self,
+        rule: i32,
+        visiting: &HashSet<i32>,
+        regexes: &mut HashMap<i32, String>,
+    ) -> Result<String, GrammarError> {
+        if let Some(regex) = regexes.get(&rule) {
+            return Ok(regex.clone());
+        }
+        if visiting.contains(&rule) {
+            return Err(GrammarError::UnsupportedRecursion(rule));
+        }
+
+        let alternatives = self
+            .productions
+            .get(&rule)
+            .ok_or(GrammarError::UndefinedRule(rule))?;
+        let visiting = visiting.update(rule);
+        let recursive = Symbol::NonTerminal(rule);
+
+        let mut prefixes = Vector::new();
+        let mut bases = Vector::new();
+        let mut suffixes = Vector::new();
+        for symbols in alternatives {
+            let occurrences = symbols
+                .iter()
+                .filter(|symbol| **symbol == recursive)
+                .count();
+            match occurrences {
+                0 => bases.push_back(self.sequence_regex(symbols, &visiting, regexes)?),
+                1 if symbols.len() > 1 && symbols.last() == Some(&recursive) => prefixes.push_back(
+                    self.sequence_regex(&symbols.take(symbols.len() - 1), &visiting, regexes)?,
+                ),
+                1 if symbols.len() > 1 && symbols.head() == Some(&recursive) => {
+                    suffixes.push_back(self.sequence_regex(&symbols.skip(1), &visiting, regexes)?)
                 }
+                _ => return Err(GrammarError::UnsupportedRecursion(rule)),
             }
         }
+
+        if bases.is_empty() {
+            return Err(GrammarError::UnsupportedRecursion(rule));
+        }
+
+        let repeated = |alternatives: &Vector<String>| {
+            if alternatives.is_empty() {
+                String::new()
+            } else {
+                format!("(?:{})*", alternatives.iter().join("|"))
+            }
+        };
+        let base = if bases.len() == 1 {
+            bases[0].clone()
+        } else {
+            format!("(?:{})", bases.iter().join("|"))
+        };
+
+        let regex = format!("{}{}{}", repeated(&prefixes), base, repeated(&suffixes));
+        regexes.insert(rule, regex.clone());
+        Ok(regex)
     }
 
-    fn parse_all(input: Vector<&String>) -> HashMap<i32, Rule> {
-        input.iter().map(|line| Self::parse(&line[..])).collect()
+    fn sequence_regex(
+        &self,
+        symbols: &Vector<Symbol>,
+        visiting: &HashSet<i32>,
+        regexes: &mut HashMap<i32, String>,
+    ) -> Result<String, GrammarError> {
+        symbols
+            .iter()
+            .map(|symbol| match symbol {
+                Symbol::Terminal(ch) if "\\.+*?()|[]{}^$#&-~".contains(*ch) => {
+                    Ok(format!("\\{}", ch))
+                }
+                Symbol::Terminal(ch) => Ok(ch.to_string()),
+                Symbol::NonTerminal(reference) => self.rule_regex(*reference, visiting, regexes),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn grammar(rules: &[&str]) -> Grammar {
+        let lines: Vector<String> = rules.iter().map(|line| line.to_string()).collect();
+        Grammar::compile(&Rule::parse_all(lines.iter().collect()).unwrap(), 0)
     }
 
-    fn parse(input: &str) -> (i32, Rule) {
-        let (number_part, rule_part) = input.split(':').collect_tuple().unwrap();
-        let number = number_part.parse::<i32>().unwrap();
-        let rule = Self::parse_rule(rule_part.trim());
-        (number, rule)
+    #[test]
+    fn test_matches() {
+        let grammar = grammar(&[
+            "0: 4 1 5",
+            "1: 2 3 | 3 2",
+            "2: 4 4 | 5 5",
+            "3: 4 5 | 5 4",
+            "4: \"a\"",
+            "5: \"b\"",
+        ]);
+        assert!(grammar.matches("ababbb"));
+        assert!(grammar.matches("abbbab"));
+        assert!(!grammar.matches("bababa"));
+        assert!(!grammar.matches("aaabbb"));
+        assert!(!grammar.matches("aaaabbb"));
     }
 
-    fn parse_rule(input: &str) -> Rule {
-        let pieces: Vector<_> = input.split('|').collect();
-        if pieces.len() == 1 {
-            let piece = pieces.head().unwrap();
-            println!("{}", piece);
-            match piece.chars().next().unwrap() {
-                ' ' => Self::parse_rule(&piece[1..]),
-                '"' => Rule::Character(piece.chars().nth(1).unwrap()),
-                ch if ch.is_ascii_digit() => Rule::Sequence(
-                    piece
-                        .trim()
-                        .split(' ')
-                        .map(|i| i.parse::<i32>().unwrap())
-                        .collect(),
+    #[test]
+    fn test_left_recursion() {
+        let grammar = grammar(&["0: 0 1 | 2", "1: \"b\"", "2: \"a\""]);
+        assert!(grammar.matches("a"));
+        assert!(grammar.matches("abbb"));
+        assert!(!grammar.matches("ba"));
+        assert!(!grammar.matches(""));
+    }
+
+    #[test]
+    fn test_parse_tree() {
+        let grammar = grammar(&["0: 1 0 | 1", "1: \"a\" | \"b\""]);
+        let leaf = |alternative, ch| ParseTree::Node {
+            rule: 1,
+            alternative,
+            children: vector!(ParseTree::Character(ch)),
+        };
+        assert_eq!(
+            grammar.parse("ba"),
+            Some(ParseTree::Node {
+                rule: 0,
+                alternative: 0,
+                children: vector!(
+                    leaf(1, 'b'),
+                    ParseTree::Node {
+                        rule: 0,
+                        alternative: 1,
+                        children: vector!(leaf(0, 'a')),
+                    }
                 ),
-                _ => panic!("Unsupported rule {}", input),
-            }
-        } else {
-            Rule::Either(pieces.iter().map(|piece| Self::parse_rule(piece)).collect())
-        }
+            })
+        );
+        assert_eq!(grammar.parse("bca"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Rule::parse("3 \"a\""),
+            Err(ParseError::MissingColon("3 \"a\"".to_owned()))
+        );
+        assert_eq!(
+            Rule::parse("x: \"a\""),
+            Err(ParseError::RuleNumber("x".to_owned()))
+        );
+        assert_eq!(
+            Rule::parse("3: 1 2 | a"),
+            Err(ParseError::UnsupportedRule("a".to_owned()))
+        );
+        assert_eq!(
+            Rule::parse("3: \"ab\""),
+            Err(ParseError::UnsupportedRule("\"ab\"".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let grammar = grammar(&[
+            "0: 1 2 | 3",
+            "1: 1 4 | 4",
+            "2: 4 | 5",
+            "3: 4 4 | 9",
+            "4: \"a\"",
+            "5: \"a\"",
+            "6: 4",
+        ]);
+        assert_eq!(
+            grammar.diagnostics(),
+            vector!(
+                Diagnostic::UndefinedRule {
+                    rule: 3,
+                    reference: 9
+                },
+                Diagnostic::Unreachable(6),
+                Diagnostic::LeftRecursion(vector!(1, 1)),
+                Diagnostic::AmbiguousAlternatives {
+                    rule: 2,
+                    first: 0,
+                    second: 1
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_exports() {
+        let regular = grammar(&["0: 1 2", "1: 3 | 3 1", "2: 2 3 | 3", "3: \"a\" | \"+\""]);
+        assert_eq!(
+            regular.to_ebnf(),
+            "r0 = r1 , r2 ;\nr1 = r3 | r3 , r1 ;\nr2 = r2 , r3 | r3 ;\nr3 = \"a\" | \"+\" ;\n"
+        );
+        assert_eq!(
+            regular.to_regex(),
+            Ok("^(?:(?:a|\\+))*(?:a|\\+)(?:a|\\+)(?:(?:a|\\+))*$".to_owned())
+        );
+        assert_eq!(
+            grammar(&["0: 1 0 2 | 1 2", "1: \"a\"", "2: \"b\""]).to_regex(),
+            Err(GrammarError::UnsupportedRecursion(0))
+        );
+        assert_eq!(
+            grammar(&["0: 1 | 1 2", "1: \"a\"", "2: 0"]).to_regex(),
+            Err(GrammarError::UnsupportedRecursion(0))
+        );
+    }
+
+    #[test]
+    fn test_enumerate() {
+        let grammar = grammar(&["0: 1 1 | 2", "1: \"a\" | \"b\"", "2: \"c\"", "3: 3 2 | 2"]);
+        assert_eq!(
+            grammar.enumerate(0, 10),
+            Ok(vector!(
+                "aa".to_owned(),
+                "ab".to_owned(),
+                "ba".to_owned(),
+                "bb".to_owned(),
+                "c".to_owned()
+            ))
+        );
+        assert_eq!(grammar.enumerate(0, 3), Err(GrammarError::TooLarge(0)));
+        assert_eq!(grammar.enumerate(3, 10), Err(GrammarError::Recursive(3)));
     }
 }
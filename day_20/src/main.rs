@@ -6,8 +6,14 @@ use im_rc::vector;
 use im_rc::HashMap;
 use im_rc::HashSet;
 use im_rc::Vector;
+use itertools::Itertools;
 use std::cmp;
+use std::env;
 use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
 use std::iter::FromIterator;
 
 fn main() {
@@ -15,52 +21,131 @@ fn main() {
 
     let input = utils::read_strings_from_param();
 
-    // println!("Solution to part one: {}", part_one(&input));
+    println!("Solution to part one: {}", part_one(&input));
     println!("Solution to part two: {}", part_two(&input));
-}
 
-fn part_one(input: &Vector<String>) -> i64 {
-    let (mut tiles, last_buffer) = input.iter().fold(
-        (HashMap::new(), Vector::new()),
-        |(mut tiles, mut buffer), line| {
-            if line.is_empty() {
-                let tile = Tile::parse(&buffer);
-                tiles.insert(tile.id, tile);
-                (tiles, Vector::new())
-            } else {
-                buffer.push_back(line);
-                (tiles, buffer)
+    let args: Vector<String> = env::args().collect();
+    let patterns = match args.iter().skip_while(|arg| *arg != "--patterns").nth(1) {
+        Some(path) => match Pattern::parse_all(
+            &utils::read_strings(path).expect("Failed to read pattern file"),
+        ) {
+            Ok(patterns) => {
+                report_patterns(&input, &patterns);
+                patterns
+            }
+            Err(error) => {
+                println!("{}", error);
+                return;
             }
         },
-    );
+        None => Pattern::parse_all(&SEA_MONSTER.lines().map(String::from).collect())
+            .expect("The sea monster is a valid pattern"),
+    };
 
-    if !last_buffer.is_empty() {
-        let tile = Tile::parse(&last_buffer);
-        tiles.insert(tile.id, tile);
+    if let Some(path) = args.iter().skip_while(|arg| *arg != "--render").nth(1) {
+        let scale = args
+            .iter()
+            .skip_while(|arg| *arg != "--scale")
+            .nth(1)
+            .map(|scale| {
+                scale
+                    .parse()
+                    .ok()
+                    .filter(|&scale: &usize| scale > 0)
+                    .expect("Scale should be a positive number")
+            })
+            .unwrap_or(4);
+        render_picture(&input, &patterns, path, scale).expect("Failed to write picture");
+        println!("Rendered image to {}", path);
+    }
+    if args.contains(&"--show".to_owned()) {
+        show_image(&input, None);
+    }
+    if args.contains(&"--show-patterns".to_owned()) {
+        show_image(&input, Some(&patterns));
     }
+}
+
+const SEA_MONSTER: &str = "Sea monster:
+                  #
+#    ##    ##    ###
+ #  #  #  #  #  #";
+
+fn part_one(input: &Vector<String>) -> i64 {
+    let tiles = parse_tiles(input);
+    let index = EdgeIndex::new(&tiles).unwrap_or_else(|error| panic!("{}", error));
+    let corners = index.corners(&tiles);
+    if corners.len() != 4 {
+        panic!("Expected 4 corner tiles but found {:?}", corners);
+    }
+    corners.iter().map(|&id| id as i64).product()
+}
 
-    let tile = tiles.values().nth(0).unwrap().clone();
+fn part_two(input: &Vector<String>) -> usize {
+    let tiles = parse_tiles(input);
+    let grid = TileGrid::assemble(&tiles).unwrap_or_else(|error| panic!("{}", error));
+    let image = Image::new(&grid);
 
-    let mut tiles_to_fit: HashSet<&i32> = tiles.keys().filter(|k| *k != &tile.id).collect();
-    let mut grid = TileGrid::new(tile);
+    let patterns = Pattern::parse_all(&SEA_MONSTER.lines().map(String::from).collect())
+        .expect("The sea monster is a valid pattern");
+    let matches = image.search_all(&patterns);
+    image.roughness(&matches)
+}
+
+fn report_patterns(input: &Vector<String>, patterns: &Vector<Pattern>) {
+    let tiles = parse_tiles(input);
+    let grid = TileGrid::assemble(&tiles).unwrap_or_else(|error| panic!("{}", error));
+    let image = Image::new(&grid);
+    let matches = image.search_all(patterns);
 
-    while !tiles_to_fit.is_empty() {
-        if let Some(next) = tiles_to_fit
+    patterns.iter().for_each(|pattern| {
+        let found: Vector<&Match> = matches
             .iter()
-            .find(|tile_id| grid.insert(tiles.get(tile_id).unwrap()))
-        {
-            println!("Fitted {} ({} left)", next, tiles_to_fit.len() - 1);
-            println!("{}", grid);
-            tiles_to_fit.remove(*next);
-        } else {
-            panic!("Could not find next tile to insert");
-        }
+            .filter(|m| m.pattern == pattern.name)
+            .collect();
+        println!(
+            "{}: {} matches covering {} cells",
+            pattern.name,
+            found.len(),
+            Match::coverage(found.iter().copied()).len()
+        );
+        found.iter().for_each(|m| println!("  {}", m));
+    });
+
+    Match::overlapping(&matches)
+        .iter()
+        .for_each(|(a, b)| println!("Overlap: {} and {}", matches[*a], matches[*b]));
+
+    println!(
+        "Cells not covered by any pattern: {}",
+        image.roughness(&matches)
+    );
+}
+
+// Prints the assembled image, with the cells of any pattern matches marked 'O'.
+fn show_image(input: &Vector<String>, patterns: Option<&Vector<Pattern>>) {
+    let tiles = parse_tiles(input);
+    let grid = TileGrid::assemble(&tiles).unwrap_or_else(|error| panic!("{}", error));
+    let image = Image::new(&grid);
+    match patterns {
+        Some(patterns) => image.render_with_pattern(&image.search_all(patterns)),
+        None => image.render(),
     }
+}
 
-    grid.corner_ids().iter().map(|&i| i as i64).product()
+fn render_picture(
+    input: &Vector<String>,
+    patterns: &Vector<Pattern>,
+    path: &str,
+    scale: usize,
+) -> io::Result<()> {
+    let tiles = parse_tiles(input);
+    let grid = TileGrid::assemble(&tiles).unwrap_or_else(|error| panic!("{}", error));
+    let matches = Image::new(&grid).search_all(patterns);
+    grid.picture(&matches, scale).write(path)
 }
 
-fn part_two(input: &Vector<String>) -> usize {
+fn parse_tiles(input: &Vector<String>) -> HashMap<i32, Tile> {
     let (mut tiles, last_buffer) = input.iter().fold(
         (HashMap::new(), Vector::new()),
         |(mut tiles, mut buffer), line| {
@@ -80,51 +165,176 @@ fn part_two(input: &Vector<String>) -> usize {
         tiles.insert(tile.id, tile);
     }
 
-    let tile = tiles.values().next().unwrap().clone();
-    let tile = tiles.get(&3413).unwrap().clone();
-    // let tile = tiles.get(&1951).unwrap().clone();
-    let mut grid = TileGrid::new(tile);
-    grid.insert_all(tiles);
+    tiles
+}
 
-    println!("Completed grid!");
-    println!("{}", grid);
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AssemblyError {
+    NoTiles,
+    AmbiguousEdge {
+        edge: String,
+        tiles: Vector<i32>,
+    },
+    AmbiguousOrientation {
+        tile: i32,
+        neighbour: i32,
+    },
+    NoFit {
+        tile: i32,
+        neighbour: i32,
+    },
+    Conflict {
+        position: (i32, i32),
+        tile: i32,
+    },
+    Disconnected {
+        placed: usize,
+        total: usize,
+    },
+    NotSquare {
+        width: i32,
+        height: i32,
+        tiles: usize,
+    },
+    Incomplete {
+        side: i32,
+        tiles: usize,
+    },
+}
 
-    let pattern_strings = vector!(
-        "                  # ",
-        "#    ##    ##    ###",
-        " #  #  #  #  #  #   "
-    );
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoTiles => write!(f, "There are no tiles to assemble"),
+            Self::AmbiguousEdge { edge, tiles } => write!(
+                f,
+                "Edge {} is shared by more than two tiles: {:?}",
+                edge, tiles
+            ),
+            Self::AmbiguousOrientation { tile, neighbour } => write!(
+                f,
+                "Tile {} fits next to tile {} in more than one orientation",
+                neighbour, tile
+            ),
+            Self::NoFit { tile, neighbour } => write!(
+                f,
+                "Tile {} shares an edge with tile {} but no orientation fits",
+                neighbour, tile
+            ),
+            Self::Conflict { position, tile } => {
+                write!(f, "Tile {} does not fit at position {:?}", tile, position)
+            }
+            Self::Disconnected { placed, total } => write!(
+                f,
+                "Only {} of {} tiles are connected to the rest",
+                placed, total
+            ),
+            Self::NotSquare {
+                width,
+                height,
+                tiles,
+            } => write!(
+                f,
+                "The {} tiles form a {}x{} grid instead of a square",
+                tiles, width, height
+            ),
+            Self::Incomplete { side, tiles } => write!(
+                f,
+                "The {} tiles leave gaps in a {}x{} grid",
+                tiles, side, side
+            ),
+        }
+    }
+}
 
-    let pattern =
-        pattern_strings
-            .iter()
-            .enumerate()
-            .fold(Vector::new(), |mut positions, (y, line)| {
-                line.chars()
-                    .enumerate()
-                    .fold(positions, |mut positions, (x, ch)| {
-                        if ch == '#' {
-                            positions.push_back((x, y));
-                        }
-                        positions
-                    })
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+impl Side {
+    fn all() -> Vector<Side> {
+        vector!(Side::Top, Side::Right, Side::Bottom, Side::Left)
+    }
+
+    fn opposite(&self) -> Side {
+        match self {
+            Self::Top => Self::Bottom,
+            Self::Right => Self::Left,
+            Self::Bottom => Self::Top,
+            Self::Left => Self::Right,
+        }
+    }
+
+    fn neighbour(&self, (x, y): (i32, i32)) -> (i32, i32) {
+        match self {
+            Self::Top => (x, y - 1),
+            Self::Right => (x + 1, y),
+            Self::Bottom => (x, y + 1),
+            Self::Left => (x - 1, y),
+        }
+    }
+}
+
+// Maps every edge, read in whichever direction sorts first, to the tiles that have it.
+#[derive(Debug)]
+struct EdgeIndex {
+    tiles_by_edge: HashMap<String, Vector<i32>>,
+}
+
+impl EdgeIndex {
+    fn new(tiles: &HashMap<i32, Tile>) -> Result<EdgeIndex, AssemblyError> {
+        let tiles_by_edge: HashMap<String, Vector<i32>> = tiles
+            .values()
+            .flat_map(|tile| {
+                Side::all()
+                    .into_iter()
+                    .map(move |side| (Self::canonical(&tile.edge(side)), tile.id))
+            })
+            .fold(HashMap::new(), |index, (edge, id)| {
+                index.update_with(edge, vector!(id), |mut ids, new| {
+                    ids.append(new);
+                    ids
+                })
             });
 
-    let image = Image::new(&grid);
-    let (monster_coordinates, image_variant) = image
-        .flip()
-        .variations()
-        .find_map(|variation| {
-            let positions = variation.search(&pattern);
-            if positions.is_empty() {
-                None
-            } else {
-                Some((positions, variation))
-            }
-        })
-        .unwrap();
+        match tiles_by_edge.iter().find(|(_, ids)| ids.len() > 2) {
+            Some((edge, ids)) => Err(AssemblyError::AmbiguousEdge {
+                edge: edge.clone(),
+                tiles: ids.clone(),
+            }),
+            None => Ok(EdgeIndex { tiles_by_edge }),
+        }
+    }
+
+    fn canonical(edge: &str) -> String {
+        let reversed: String = edge.chars().rev().collect();
+        cmp::min(edge.to_owned(), reversed)
+    }
 
-    image_variant.count_pos_without_monster(&pattern, &monster_coordinates)
+    fn neighbour(&self, tile: &Tile, side: Side) -> Option<i32> {
+        self.tiles_by_edge
+            .get(&Self::canonical(&tile.edge(side)))
+            .and_then(|ids| ids.iter().find(|&&id| id != tile.id).copied())
+    }
+
+    fn corners(&self, tiles: &HashMap<i32, Tile>) -> Vector<i32> {
+        tiles
+            .values()
+            .filter(|tile| {
+                Side::all()
+                    .iter()
+                    .filter(|side| self.neighbour(tile, **side).is_none())
+                    .count()
+                    == 2
+            })
+            .map(|tile| tile.id)
+            .sorted()
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -147,11 +357,11 @@ impl fmt::Display for TileGrid {
                         .get(&(x, y))
                         .map(|t| format!(" {:4}  ", t.id))
                         .unwrap_or(format!("({:+},{:+})", x, y))
-                );
+                )?;
             }
-            writeln!(f);
+            writeln!(f)?;
         }
-        write!(f, "")
+        Ok(())
     }
 }
 
@@ -188,26 +398,6 @@ impl TileGrid {
         self.max_y - self.min_y + 1
     }
 
-    fn insert_all(&mut self, tiles: HashMap<i32, Tile>) {
-        let current_tiles: HashSet<i32> = self.tiles.values().map(|tile| tile.id).collect();
-        let mut tiles_to_fit: HashSet<i32> = tiles
-            .keys()
-            .copied()
-            .collect::<HashSet<_>>()
-            .difference(current_tiles);
-
-        while !tiles_to_fit.is_empty() {
-            if let Some(&next) = tiles_to_fit
-                .iter()
-                .find(|tile_id| self.insert(tiles.get(tile_id).unwrap()))
-            {
-                tiles_to_fit.remove(&next);
-            } else {
-                panic!("Could not find next tile to insert");
-            }
-        }
-    }
-
     fn insert_at(&mut self, position: (i32, i32), tile: Tile) {
         // println!("Inserting {} at {:?}", tile.id, position);
         self.tiles.insert(position, tile);
@@ -217,44 +407,112 @@ impl TileGrid {
         self.max_y = cmp::max(self.max_y, position.1);
     }
 
-    fn insert(&mut self, tile: &Tile) -> bool {
-        if let Some((position, variation)) = tile.variations().find_map(|variation| {
-            self.positions_to_fill()
-                .iter()
-                .find(|(x, y)| self.fits((*x, *y), &variation))
-                .map(|(x, y)| ((*x, *y), variation))
-        }) {
-            self.insert_at(position, variation);
-            true
-        } else {
-            false
-        }
+    // Assembles the grid starting from the tile with the lowest id.
+    fn assemble(tiles: &HashMap<i32, Tile>) -> Result<TileGrid, AssemblyError> {
+        let start = tiles
+            .keys()
+            .min()
+            .map(|id| &tiles[id])
+            .ok_or(AssemblyError::NoTiles)?;
+        TileGrid::assemble_from(tiles, start)
     }
 
-    fn positions_to_fill(&self) -> HashSet<(i32, i32)> {
-        let positions: Vector<_> = self
-            .tiles
-            .keys()
-            .flat_map(|(x, y)| vector!((x + 1, *y), (x - 1, *y), (*x, y + 1), (*x, y - 1)))
-            .filter(|(x, y)| !self.tiles.contains_key(&(*x, *y)))
-            .collect();
+    // Places `start` as it is and grows the grid outwards, looking up the neighbour on each
+    // side through the edge index.
+    fn assemble_from(tiles: &HashMap<i32, Tile>, start: &Tile) -> Result<TileGrid, AssemblyError> {
+        let index = EdgeIndex::new(tiles)?;
+        let start = start.clone();
+
+        let mut placed = hashmap!(start.id => (0, 0));
+        let mut grid = TileGrid::new(start);
+        let mut ambiguous = None;
+
+        // A tile that fits in several orientations (e.g. across a palindromic edge) is
+        // skipped until a later round, when more of its neighbours are in place.
+        while placed.len() < tiles.len() {
+            let placed_before = placed.len();
+            let mut pending: Vector<(i32, i32)> = grid.tiles.keys().copied().collect();
+
+            while let Some(position) = pending.pop_front() {
+                let tile = grid.tiles[&position].clone();
+
+                for side in Side::all() {
+                    let neighbour_id = match index.neighbour(&tile, side) {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    let neighbour_position = side.neighbour(position);
+
+                    if let Some(existing) = placed.get(&neighbour_id) {
+                        if *existing == neighbour_position {
+                            continue;
+                        }
+                        return Err(AssemblyError::Conflict {
+                            position: neighbour_position,
+                            tile: neighbour_id,
+                        });
+                    }
 
-        let (doubles, uniques) = positions.iter().copied().fold(
-            (HashSet::new(), HashSet::new()),
-            |(mut doubles, mut uniques), position| {
-                if uniques.contains(&position) {
-                    doubles.insert(position);
-                } else {
-                    uniques.insert(position);
+                    let edge = tile.edge(side);
+                    let orientations: Vector<Tile> = tiles[&neighbour_id]
+                        .variations()
+                        .filter(|variation| variation.edge(side.opposite()) == edge)
+                        .unique_by(|variation| variation.to_string())
+                        .collect();
+                    if orientations.is_empty() {
+                        return Err(AssemblyError::NoFit {
+                            tile: tile.id,
+                            neighbour: neighbour_id,
+                        });
+                    }
+
+                    let fitting: Vector<Tile> = orientations
+                        .into_iter()
+                        .filter(|variation| grid.fits(neighbour_position, variation))
+                        .collect();
+                    if fitting.is_empty() || grid.tiles.contains_key(&neighbour_position) {
+                        return Err(AssemblyError::Conflict {
+                            position: neighbour_position,
+                            tile: neighbour_id,
+                        });
+                    }
+                    if fitting.len() > 1 {
+                        ambiguous = Some(AssemblyError::AmbiguousOrientation {
+                            tile: tile.id,
+                            neighbour: neighbour_id,
+                        });
+                        continue;
+                    }
+
+                    grid.insert_at(neighbour_position, fitting[0].clone());
+                    placed.insert(neighbour_id, neighbour_position);
+                    pending.push_back(neighbour_position);
                 }
-                (doubles, uniques)
-            },
-        );
+            }
 
-        if doubles.is_empty() {
-            uniques
+            if placed.len() == placed_before {
+                break;
+            }
+        }
+
+        if placed.len() != tiles.len() {
+            Err(ambiguous.unwrap_or(AssemblyError::Disconnected {
+                placed: placed.len(),
+                total: tiles.len(),
+            }))
+        } else if grid.width() != grid.height() {
+            Err(AssemblyError::NotSquare {
+                width: grid.width(),
+                height: grid.height(),
+                tiles: tiles.len(),
+            })
+        } else if (grid.width() * grid.height()) as usize != tiles.len() {
+            Err(AssemblyError::Incomplete {
+                side: grid.width(),
+                tiles: tiles.len(),
+            })
         } else {
-            doubles
+            Ok(grid)
         }
     }
 
@@ -297,6 +555,7 @@ impl TileGrid {
         true
     }
 
+    #[cfg(test)]
     fn corners(&self) -> Vector<(i32, i32)> {
         vector!(
             (self.min_x, self.min_y),
@@ -306,6 +565,7 @@ impl TileGrid {
         )
     }
 
+    #[cfg(test)]
     fn corner_ids(&self) -> Vector<i32> {
         self.corners()
             .iter()
@@ -313,6 +573,60 @@ impl TileGrid {
             .collect()
     }
 
+    // Draws every tile including its borders; matches are given in image coordinates, which skip the borders.
+    fn picture(&self, matches: &Vector<Match>, scale: usize) -> Picture {
+        let covered = Match::coverage(matches.iter());
+        let (tile_width, tile_height) = self
+            .normal_get(0, 0)
+            .map(|tile| (tile.width as usize, tile.height as usize))
+            .unwrap_or((0, 0));
+        let mut picture = Picture::new(
+            self.width() as usize * tile_width * scale,
+            self.height() as usize * tile_height * scale,
+        );
+
+        for (grid_x, grid_y) in (0..self.height())
+            .flat_map(|y| (0..self.width()).map(move |x| (x as usize, y as usize)))
+        {
+            let tile = self.normal_get(grid_x as i32, grid_y as i32).unwrap();
+            for (tile_x, tile_y) in
+                (0..tile_height).flat_map(|y| (0..tile_width).map(move |x| (x, y)))
+            {
+                let set = tile
+                    .positions
+                    .contains(&TilePosition(tile_x as i32, tile_y as i32));
+                let border = tile_x == 0
+                    || tile_y == 0
+                    || tile_x == tile_width - 1
+                    || tile_y == tile_height - 1;
+                let colour = if border {
+                    if set {
+                        BORDER_SET
+                    } else {
+                        BORDER_EMPTY
+                    }
+                } else if covered.contains(&(
+                    grid_x * (tile_width - 2) + tile_x - 1,
+                    grid_y * (tile_height - 2) + tile_y - 1,
+                )) {
+                    MATCHED
+                } else if set {
+                    SET
+                } else {
+                    EMPTY
+                };
+                picture.fill(
+                    (grid_x * tile_width + tile_x) * scale,
+                    (grid_y * tile_height + tile_y) * scale,
+                    scale,
+                    colour,
+                );
+            }
+        }
+
+        picture
+    }
+
     fn new(initial_tile: Tile) -> TileGrid {
         TileGrid {
             tiles: hashmap!( (0,0) => initial_tile ),
@@ -355,27 +669,19 @@ impl Iterator for TileIterator {
     type Item = Tile;
 
     fn next(&mut self) -> Option<Tile> {
-        if !self.flipped {
-            if self.rotated <= 3 {
-                let result = Some(self.tile.clone());
-                self.tile = self.tile.rotate();
-                self.rotated += 1;
-                result
-            } else {
-                let result = Some(self.tile.clone());
-                self.tile = self.tile.flip();
-                self.rotated = 0;
-                self.flipped = true;
-                result
-            }
-        } else if self.rotated < 3 {
-            let result = Some(self.tile.clone());
-            self.tile = self.tile.rotate();
-            self.rotated += 1;
-            result
-        } else {
-            None
+        if self.flipped && self.rotated == 4 {
+            return None;
         }
+
+        let result = self.tile.clone();
+        self.tile = self.tile.rotate();
+        self.rotated += 1;
+        if self.rotated == 4 && !self.flipped {
+            self.tile = self.tile.flip();
+            self.rotated = 0;
+            self.flipped = true;
+        }
+        Some(result)
     }
 }
 
@@ -414,6 +720,15 @@ impl Tile {
         }
     }
 
+    fn edge(&self, side: Side) -> String {
+        match side {
+            Side::Top => self.top(),
+            Side::Right => self.right(),
+            Side::Bottom => self.bottom(),
+            Side::Left => self.left(),
+        }
+    }
+
     fn bottom(&self) -> String {
         let chars = (0..self.width).map(|i| {
             if self.positions.contains(&TilePosition(i, self.height - 1)) {
@@ -505,57 +820,168 @@ impl fmt::Display for Tile {
                 write!(
                     f,
                     "{}",
-                    if self.positions.contains(&TilePosition(x as i32, y as i32)) {
+                    if self.positions.contains(&TilePosition(x, y)) {
                         '#'
                     } else {
                         '.'
                     }
-                );
+                )?;
             }
-            writeln!(f);
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternError {
+    NoCells(String),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoCells(name) => write!(f, "Pattern '{}' has no '#' cells", name),
         }
-        write!(f, "")
     }
 }
 
 #[derive(Clone)]
 struct Image(Vector<Vector<bool>>);
 
-struct ImageIterator {
-    image: Image,
-    rotated: i8,
-    flipped: bool,
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    name: String,
+    offsets: Vector<(usize, usize)>,
 }
 
-impl Iterator for ImageIterator {
-    type Item = Image;
+impl Pattern {
+    // Patterns are separated by blank lines; a first line ending in ':' names the pattern.
+    fn parse_all(input: &Vector<String>) -> Result<Vector<Pattern>, PatternError> {
+        input
+            .iter()
+            .group_by(|line| line.is_empty())
+            .into_iter()
+            .filter(|(empty, _)| !empty)
+            .enumerate()
+            .map(|(i, (_, lines))| Self::parse(i, lines.collect()))
+            .collect()
+    }
 
-    fn next(&mut self) -> Option<Image> {
-        if !self.flipped {
-            if self.rotated <= 3 {
-                println!("rotating");
-                let result = Some(self.image.clone());
-                self.image = self.image.rotate();
-                self.rotated += 1;
-                result
-            } else {
-                println!("flipping");
-                let result = Some(self.image.clone());
-                self.image = self.image.flip();
-                self.rotated = 0;
-                self.flipped = true;
-                result
+    fn parse(index: usize, lines: Vector<&String>) -> Result<Pattern, PatternError> {
+        let (name, rows) = match lines.head() {
+            Some(head) if head.ends_with(':') => {
+                (head.trim_end_matches(':').trim().to_owned(), lines.skip(1))
             }
-        } else if self.rotated < 3 {
-            println!("rotating");
-            let result = Some(self.image.clone());
-            self.image = self.image.rotate();
-            self.rotated += 1;
-            result
-        } else {
-            None
+            _ => (format!("Pattern {}", index + 1), lines),
+        };
+
+        let offsets: Vector<_> = rows
+            .iter()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row.chars()
+                    .enumerate()
+                    .filter(|(_, ch)| *ch == '#')
+                    .map(move |(x, _)| (x, y))
+            })
+            .collect();
+
+        if offsets.is_empty() {
+            return Err(PatternError::NoCells(name));
+        }
+        Ok(Pattern { name, offsets })
+    }
+
+    fn width(&self) -> usize {
+        self.offsets.iter().map(|(x, _)| x + 1).max().unwrap_or(0)
+    }
+
+    fn height(&self) -> usize {
+        self.offsets.iter().map(|(_, y)| y + 1).max().unwrap_or(0)
+    }
+
+    fn rotate(&self) -> Pattern {
+        let height = self.height();
+        Pattern {
+            name: self.name.clone(),
+            offsets: self
+                .offsets
+                .iter()
+                .map(|(x, y)| (height - 1 - y, *x))
+                .collect(),
+        }
+    }
+
+    fn flip(&self) -> Pattern {
+        let width = self.width();
+        Pattern {
+            name: self.name.clone(),
+            offsets: self
+                .offsets
+                .iter()
+                .map(|(x, y)| (width - 1 - x, *y))
+                .collect(),
         }
     }
+
+    // All eight orientations as (rotation, flipped, pattern); symmetric patterns yield fewer.
+    fn orientations(&self) -> Vector<(usize, bool, Pattern)> {
+        let flipped = self.flip();
+        (0..4)
+            .scan((self.clone(), flipped), |(pattern, flipped), rotation| {
+                let result = vector!(
+                    (rotation, false, pattern.clone()),
+                    (rotation, true, flipped.clone())
+                );
+                *pattern = pattern.rotate();
+                *flipped = flipped.rotate();
+                Some(result)
+            })
+            .flatten()
+            .unique_by(|(_, _, pattern)| pattern.offsets.iter().copied().sorted().collect_vec())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Match {
+    pattern: String,
+    rotation: usize,
+    flipped: bool,
+    position: (usize, usize),
+    cells: Vector<(usize, usize)>,
+}
+
+impl Match {
+    fn coverage<'a>(matches: impl Iterator<Item = &'a Match>) -> HashSet<(usize, usize)> {
+        matches.flat_map(|m| m.cells.iter().copied()).collect()
+    }
+
+    fn overlapping(matches: &Vector<Match>) -> Vector<(usize, usize)> {
+        (0..matches.len())
+            .tuple_combinations()
+            .filter(|&(a, b)| {
+                matches[a]
+                    .cells
+                    .iter()
+                    .any(|cell| matches[b].cells.contains(cell))
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:?} rotated {} degrees{}",
+            self.pattern,
+            self.position,
+            self.rotation * 90,
+            if self.flipped { ", flipped" } else { "" }
+        )
+    }
 }
 
 impl Image {
@@ -578,15 +1004,8 @@ impl Image {
         println!();
     }
 
-    fn render_with_pattern(
-        &self,
-        pattern: Vector<(usize, usize)>,
-        positions: Vector<(usize, usize)>,
-    ) {
-        let monster_positions: HashSet<(usize, usize)> = positions
-            .iter()
-            .flat_map(|(x, y)| pattern.iter().map(move |(dx, dy)| (x + dx, y + dy)))
-            .collect();
+    fn render_with_pattern(&self, matches: &Vector<Match>) {
+        let monster_positions = Match::coverage(matches.iter());
 
         print!("   ");
         for x in 0..self.width() {
@@ -613,64 +1032,44 @@ impl Image {
         println!();
     }
 
-    fn count_pos_without_monster(
-        &self,
-        pattern: &Vector<(usize, usize)>,
-        positions: &Vector<(usize, usize)>,
-    ) -> usize {
-        let monster_positions: HashSet<(usize, usize)> = positions
+    // Searches the image once per orientation of each pattern instead of transforming the image.
+    fn search_all(&self, patterns: &Vector<Pattern>) -> Vector<Match> {
+        patterns
             .iter()
-            .flat_map(|(x, y)| pattern.iter().map(move |(dx, dy)| (x + dx, y + dy)))
-            .collect();
-
-        self.0
-            .iter()
-            .enumerate()
-            .map(|(y, row)| {
-                row.iter()
-                    .enumerate()
-                    .filter(|(x, &e)| e && !monster_positions.contains(&(*x, y)))
-                    .count()
+            .flat_map(|pattern| pattern.orientations())
+            .flat_map(|(rotation, flipped, pattern)| {
+                self.search(&pattern.offsets)
+                    .into_iter()
+                    .map(move |(x, y)| Match {
+                        pattern: pattern.name.clone(),
+                        rotation,
+                        flipped,
+                        position: (x, y),
+                        cells: pattern
+                            .offsets
+                            .iter()
+                            .map(|(dx, dy)| (x + dx, y + dy))
+                            .collect(),
+                    })
             })
-            .sum()
-    }
-
-    fn variations(self) -> impl Iterator<Item = Image> {
-        ImageIterator {
-            image: self,
-            rotated: 0,
-            flipped: false,
-        }
-    }
-
-    fn flip(&self) -> Image {
-        let reversed: Vector<Vector<_>> = self
-            .0
-            .iter()
-            .map(|row| row.iter().rev().copied().collect())
-            .collect();
-        Image(reversed)
+            .collect()
     }
 
-    fn rotate(&self) -> Image {
-        let rotated = (0..self.width())
-            .map(|y| {
-                (0..self.height())
-                    .map(|x| self.get(self.width() - y - 1, x))
-                    .collect()
-            })
-            .collect();
-
-        Image(rotated)
+    fn roughness(&self, matches: &Vector<Match>) -> usize {
+        let covered = Match::coverage(matches.iter());
+        (0..self.height())
+            .flat_map(|y| (0..self.width()).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.get(x, y) && !covered.contains(&(x, y)))
+            .count()
     }
 
     fn search(&self, pattern: &Vector<(usize, usize)>) -> Vector<(usize, usize)> {
         let pattern_height = *pattern.iter().map(|(_, y)| y).max().unwrap_or(&0);
         let pattern_width = *pattern.iter().map(|(x, _)| x).max().unwrap_or(&0);
 
-        (0..self.height() - pattern_height)
-            .flat_map(|y| (0..self.width() - pattern_width).map(move |x| (x, y)))
-            .filter(|pos| self.check_pattern(pos, &pattern))
+        (0..self.height().saturating_sub(pattern_height))
+            .flat_map(|y| (0..self.width().saturating_sub(pattern_width)).map(move |x| (x, y)))
+            .filter(|pos| self.check_pattern(pos, pattern))
             .collect()
     }
 
@@ -704,3 +1103,227 @@ impl Image {
         Image(states)
     }
 }
+
+const EMPTY: [u8; 3] = [0x0b, 0x2e, 0x59];
+const SET: [u8; 3] = [0x7f, 0xb8, 0xe6];
+const BORDER_EMPTY: [u8; 3] = [0x30, 0x30, 0x30];
+const BORDER_SET: [u8; 3] = [0x80, 0x80, 0x80];
+const MATCHED: [u8; 3] = [0xe6, 0x39, 0x46];
+
+struct Picture {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Picture {
+    fn new(width: usize, height: usize) -> Picture {
+        Picture {
+            width,
+            height,
+            pixels: vec![EMPTY; width * height],
+        }
+    }
+
+    #[cfg(test)]
+    fn get(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
+    }
+
+    fn fill(&mut self, x: usize, y: usize, size: usize, colour: [u8; 3]) {
+        for row in y..y + size {
+            let start = row * self.width + x;
+            self.pixels[start..start + size].fill(colour);
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        self.pixels.iter().flatten().copied().collect()
+    }
+
+    fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(self.data());
+        ppm
+    }
+
+    // The format is picked from the extension: PNG for .png, binary PPM otherwise.
+    fn write(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        if path.to_lowercase().ends_with(".png") {
+            let mut encoder = png::Encoder::new(writer, self.width as u32, self.height as u32);
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .and_then(|mut png| png.write_image_data(&self.data()))
+                .map_err(io::Error::other)
+        } else {
+            writer.write_all(&self.to_ppm())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn test_tiles() -> HashMap<i32, Tile> {
+        parse_tiles(&utils::read_strings("input/test.txt").unwrap())
+    }
+
+    #[test]
+    fn test_corners() {
+        let tiles = test_tiles();
+        let index = EdgeIndex::new(&tiles).unwrap();
+        assert_eq!(index.corners(&tiles), vector!(1171, 1951, 2971, 3079));
+
+        let grid = TileGrid::assemble(&tiles).unwrap();
+        assert_eq!(
+            grid.corner_ids()
+                .iter()
+                .copied()
+                .sorted()
+                .collect::<Vector<_>>(),
+            index.corners(&tiles)
+        );
+    }
+
+    #[test]
+    fn test_assembly_from_any_tile() {
+        let tiles = test_tiles();
+        let index = EdgeIndex::new(&tiles).unwrap();
+        let patterns =
+            Pattern::parse_all(&SEA_MONSTER.lines().map(String::from).collect()).unwrap();
+
+        tiles.values().for_each(|start| {
+            let grid = TileGrid::assemble_from(&tiles, start).unwrap();
+            assert_eq!((grid.width(), grid.height()), (3, 3));
+            assert_eq!(
+                grid.corner_ids()
+                    .iter()
+                    .copied()
+                    .sorted()
+                    .collect::<Vector<_>>(),
+                index.corners(&tiles)
+            );
+
+            let image = Image::new(&grid);
+            assert_eq!(image.roughness(&image.search_all(&patterns)), 273);
+        });
+
+        let without_first = tiles.without(&1171);
+        assert_eq!(
+            TileGrid::assemble(&without_first).unwrap_err(),
+            AssemblyError::Incomplete { side: 3, tiles: 8 }
+        );
+        assert_eq!(
+            TileGrid::assemble(&tiles.without(&2971).without(&1489).without(&1171)).unwrap_err(),
+            AssemblyError::NotSquare {
+                width: 3,
+                height: 2,
+                tiles: 6
+            }
+        );
+    }
+
+    #[test]
+    fn test_variations() {
+        let tile = test_tiles()[&1427].clone();
+        assert_eq!(
+            tile.variations()
+                .map(|variation| variation.to_string())
+                .unique()
+                .count(),
+            8
+        );
+    }
+
+    #[test]
+    fn test_pattern_parsing() {
+        let input: Vector<String> = vector!("Corner:", "##", "#", "", "#.#")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let patterns = Pattern::parse_all(&input).unwrap();
+        assert_eq!(
+            patterns,
+            vector!(
+                Pattern {
+                    name: "Corner".to_owned(),
+                    offsets: vector!((0, 0), (1, 0), (0, 1))
+                },
+                Pattern {
+                    name: "Pattern 2".to_owned(),
+                    offsets: vector!((0, 0), (2, 0))
+                }
+            )
+        );
+        assert_eq!(patterns[0].orientations().len(), 4);
+        assert_eq!(patterns[1].orientations().len(), 2);
+
+        let blank: Vector<String> = vector!("#", "", "Blank:", "...")
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let error = Pattern::parse_all(&blank).unwrap_err();
+        assert_eq!(error, PatternError::NoCells("Blank".to_owned()));
+        assert_eq!(error.to_string(), "Pattern 'Blank' has no '#' cells");
+    }
+
+    #[test]
+    fn test_pattern_search() {
+        let grid = TileGrid::assemble(&test_tiles()).unwrap();
+        let image = Image::new(&grid);
+        let monster = Pattern::parse_all(&SEA_MONSTER.lines().map(String::from).collect()).unwrap();
+        assert_eq!(monster[0].orientations().len(), 8);
+
+        let matches = image.search_all(&monster);
+        assert_eq!(matches.len(), 2);
+        assert!(matches
+            .iter()
+            .all(|m| (m.rotation, m.flipped) == (matches[0].rotation, matches[0].flipped)));
+        assert_eq!(Match::coverage(matches.iter()).len(), 30);
+        assert_eq!(image.roughness(&matches), 273);
+        assert!(Match::overlapping(&matches).is_empty());
+
+        let row = "####".to_owned();
+        let bar = Pattern::parse(0, vector!(&row)).unwrap();
+        let bars = image.search_all(&vector!(bar));
+        assert!(!Match::overlapping(&bars).is_empty());
+    }
+
+    #[test]
+    fn test_picture() {
+        let grid = TileGrid::assemble(&test_tiles()).unwrap();
+        let monster = Pattern::parse_all(&SEA_MONSTER.lines().map(String::from).collect()).unwrap();
+        let matches = Image::new(&grid).search_all(&monster);
+        let picture = grid.picture(&matches, 2);
+        assert_eq!((picture.width, picture.height), (60, 60));
+
+        let ppm = picture.to_ppm();
+        assert!(ppm.starts_with(b"P6\n60 60\n255\n"));
+        assert_eq!(ppm.len(), "P6\n60 60\n255\n".len() + 60 * 60 * 3);
+
+        let tile = grid.normal_get(0, 0).unwrap();
+        let corner = if tile.positions.contains(&TilePosition(0, 0)) {
+            BORDER_SET
+        } else {
+            BORDER_EMPTY
+        };
+        assert_eq!(picture.get(1, 1), corner);
+
+        let (x, y) = matches[0].cells[0];
+        let (x, y) = ((x / 8 * 10 + x % 8 + 1) * 2, (y / 8 * 10 + y % 8 + 1) * 2);
+        assert_eq!(picture.get(x, y), MATCHED);
+        assert_eq!(picture.get(x + 1, y + 1), MATCHED);
+    }
+
+    #[test]
+    fn test_parts() {
+        let input = utils::read_strings("input/test.txt").unwrap();
+        assert_eq!(part_one(&input), 20899048083289);
+        assert_eq!(part_two(&input), 273);
+    }
+}
@@ -1,11 +1,15 @@
 extern crate im_rc;
 
-use im_rc::hashset;
-use im_rc::HashMap;
 use im_rc::HashSet;
 use im_rc::Vector;
 use itertools::Itertools;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp;
+use std::env;
 use std::fmt;
+use std::fs;
+use std::io;
 
 fn main() {
     println!("--- [AoC 2020] Day 22: Crab Combat ---");
@@ -14,242 +18,529 @@ fn main() {
 
     println!("Solution to part one: {}", part_one(&input));
     println!("Solution to part two: {}", part_two(&input));
+
+    let args: Vector<String> = env::args().collect();
+    let variant = if args.contains(&"--combat".to_owned()) {
+        Variant::Combat
+    } else {
+        Variant::Recursive
+    };
+
+    if args.contains(&"--narrate".to_owned()) {
+        let decks = Deck::parse_all(&input);
+        let result = Game::new(variant).play(decks, Some(&mut Narrator));
+        println!("{}", result);
+    }
+
+    if let Some(path) = args.iter().skip_while(|arg| *arg != "--record").nth(1) {
+        let mut recorder = Recorder::new(variant);
+        let result = Game::new(variant).play(Deck::parse_all(&input), Some(&mut recorder));
+        fs::write(path, recorder.to_json_lines()).expect("Failed to write replay");
+        println!("{}, replay written to {}", result, path);
+    }
+
+    if let Some(path) = args.iter().skip_while(|arg| *arg != "--replay").nth(1) {
+        let log = fs::read_to_string(path).expect("Failed to read replay");
+        let replay = Replay::parse(&log).unwrap_or_else(|error| panic!("{}", error));
+        step_through(&replay, args.contains(&"--step".to_owned()));
+    }
+}
+
+// Prints the replay one event at a time, waiting for enter between events when stepping.
+fn step_through(replay: &Replay, step: bool) {
+    for event in &replay.events {
+        println!("{}", replay.explain(event));
+        if step {
+            let mut line = String::new();
+            io::stdin()
+                .read_line(&mut line)
+                .expect("Failed to read from stdin");
+        }
+    }
+    if let Some(winner) = replay.winner() {
+        println!("{} won the replayed game", winner);
+    }
 }
 
 fn part_one(input: &Vector<String>) -> usize {
-    let decks = Deck::parse_all(input);
-    println!("{}", decks.iter().join("\n\n"));
-    Deck::play(1, decks)
+    Game::new(Variant::Combat)
+        .play(Deck::parse_all(input), None)
+        .score
 }
 
 fn part_two(input: &Vector<String>) -> usize {
-    let original_decks = Deck::parse_all(input);
-    println!("{}\n", original_decks.iter().join("\n\n"));
-    let winner = Deck::play_rec(1, 1, original_decks, HashMap::new());
-
-    println!("== Post-game results ==");
-    println!(
-        "winners {} deck: {}",
-        winner.player,
-        winner.cards.iter().join(", ")
-    );
-    winner.score()
+    Game::new(Variant::Recursive)
+        .play(Deck::parse_all(input), None)
+        .score
 }
 
-#[derive(Clone, Debug)]
-struct Deck {
-    player: String,
+// Rules for any number of players, which reduce to the puzzle rules for two:
+// - every player with cards left plays their top card, players without cards drop out;
+// - the highest card wins the round, a tie goes to the player seated first;
+// - in the recursive variant a sub-game with all remaining players decides the round when each of
+//   them holds at least as many cards as the value they played;
+// - the winner puts their own card at the bottom of their deck first, then the others high to low;
+// - a game ends as soon as the table repeats, won by the first seat. The puzzle only asks this of
+//   the recursive variant, but a plain game that repeats would otherwise go on for ever.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Variant {
+    Combat,
+    Recursive,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GameResult {
+    winner: String,
     cards: Vector<usize>,
+    score: usize,
+    rounds: usize,
+    total_rounds: usize,
+    sub_games: usize,
 }
 
-impl Deck {
-    fn parse_all(input: &Vector<String>) -> Vector<Deck> {
-        itertools::unfold(input.iter(), |iterator| {
-            let lines: Vector<_> = iterator
-                .by_ref()
-                .take_while(|line| !line.is_empty())
-                .collect();
-            if lines.is_empty() {
-                None
-            } else {
-                Some(Deck::parse(&lines))
-            }
-        })
-        .collect()
+impl fmt::Display for GameResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} wins with score {} after {} rounds ({} rounds in total over {} sub-games)",
+            self.winner, self.score, self.rounds, self.total_rounds, self.sub_games
+        )
     }
+}
 
-    fn parse(input: &Vector<&String>) -> Deck {
-        let name = input.head().unwrap().replace(":", "");
-        let cards = input
-            .iter()
-            .skip(1)
-            .take_while(|line| !line.is_empty())
-            .map(|l| l.parse::<usize>().unwrap())
-            .collect();
-        Deck {
-            player: name,
-            cards,
-        }
+// All methods default to doing nothing so observers only implement the events they care about.
+trait Observer {
+    fn game_started(&mut self, _game: usize, _decks: &Vector<Deck>) {}
+    fn round_started(&mut self, _game: usize, _round: usize, _decks: &Vector<Deck>) {}
+    fn cards_played(&mut self, _game: usize, _cards: &Vector<(String, usize)>) {}
+    fn repeated_state(&mut self, _game: usize) {}
+    fn round_won(&mut self, _game: usize, _round: usize, _winner: &str) {}
+    fn game_won(&mut self, _game: usize, _winner: &Deck) {}
+}
+
+struct Narrator;
+
+impl Observer for Narrator {
+    fn game_started(&mut self, game: usize, _decks: &Vector<Deck>) {
+        println!("=== Game {} ===\n", game);
     }
 
-    fn play(round: usize, mut players: Vector<Deck>) -> usize {
-        println!("-- Round {:4} --", round);
-        for deck in &players {
+    fn round_started(&mut self, game: usize, round: usize, decks: &Vector<Deck>) {
+        println!("-- Round {} (Game {}) --", round, game);
+        for deck in decks {
             println!("{}'s deck: {}", deck.player, deck.cards.iter().join(", "));
         }
+    }
 
-        let cards: Vector<_> = players
-            .iter_mut()
-            .filter_map(|deck| {
-                deck.next_card()
-                    .map(move |card| (deck.player.to_owned(), card))
-            })
-            .collect();
-
-        for (name, card) in &cards {
+    fn cards_played(&mut self, _game: usize, cards: &Vector<(String, usize)>) {
+        for (name, card) in cards {
             println!("{} plays: {}", name, card)
         }
+    }
 
-        let winner = cards
-            .iter()
-            .max_by_key(|(_name, card)| card)
-            .map(|(name, _)| name)
-            .unwrap();
-
-        println!("{} wins the round", winner);
+    fn repeated_state(&mut self, game: usize) {
+        println!("Repeated decks in game {}", game);
+    }
 
-        let winning_deck = players
-            .iter_mut()
-            .find(|deck| &deck.player == winner)
-            .unwrap();
-        let winners_cards: Vector<_> = cards
-            .iter()
-            .map(|(_name, card)| card)
-            .sorted()
-            .rev()
-            .cloned()
-            .collect();
+    fn round_won(&mut self, game: usize, round: usize, winner: &str) {
+        println!("{} wins round {} of game {}!\n", winner, round, game);
+    }
 
-        winning_deck.add_cards(winners_cards);
+    fn game_won(&mut self, game: usize, winner: &Deck) {
+        println!("The winner of game {} is {}!", game, winner.player);
+        if game > 1 {
+            println!("\n...anyway, back to the previous game\n");
+        }
+    }
+}
 
-        players.retain(|deck| !deck.is_empty());
-        if players.len() > 1 {
-            Deck::play(round + 1, players)
-        } else {
-            println!("== Post-game results ==");
-            for deck in &players {
-                println!("{}'s deck: {}", deck.player, deck.cards.iter().join(", "));
-            }
+// A game in progress; `played` holds the cards on the table while a sub-game decides the round.
+struct Frame {
+    game: usize,
+    round: usize,
+    decks: Vector<Deck>,
+    seen: HashSet<Vector<Vector<usize>>>,
+    played: Vector<(String, usize)>,
+}
 
-            players.head().unwrap().score()
+impl Frame {
+    fn new(game: usize, mut decks: Vector<Deck>) -> Frame {
+        decks.retain(|deck| !deck.is_empty());
+        Frame {
+            game,
+            round: 0,
+            decks,
+            seen: HashSet::new(),
+            played: Vector::new(),
         }
     }
 
-    fn play_rec(
+    // The cards of every deck, which share their structure with the decks so remembering a state is
+    // cheap. Whole states are compared, so a hash collision can't pass for a repeat.
+    fn state(&self) -> Vector<Vector<usize>> {
+        self.decks.iter().map(|deck| deck.cards.clone()).collect()
+    }
+}
+
+// One line of a replay log. Rounds are written once they are decided, so a round settled by a
+// sub-game appears after all rounds of that sub-game.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ReplayEvent {
+    Start {
+        variant: Variant,
+        decks: Vector<Deck>,
+    },
+    Round {
+        game: usize,
         round: usize,
+        decks: Vector<Deck>,
+        played: Vector<(String, usize)>,
+        sub_game: Option<usize>,
+        winner: String,
+    },
+    Repeated {
         game: usize,
-        mut players: Vector<Deck>,
-        previous_states: HashMap<String, HashSet<Vector<usize>>>,
-    ) -> Deck {
-        let identical_round = players.iter().find(|player| {
-            previous_states
-                .get(&player.player[..])
-                .map(|states| states.contains(&player.cards))
-                .unwrap_or(false)
-        });
+    },
+    GameWon {
+        game: usize,
+        winner: String,
+        cards: Vector<usize>,
+    },
+}
 
-        if round == 1 {
-            println!("=== Game {} ===\n", game);
-        }
-        println!("-- Round {} (Game {}) --", round, game);
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ReplayError {
+    line: usize,
+    message: String,
+}
 
-        for deck in &players {
-            println!("{}'s deck: {}", deck.player, deck.cards.iter().join(", "));
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid replay on line {}: {}", self.line, self.message)
+    }
+}
+
+// Rounds in progress are kept on a stack because sub-games start before their parent round ends.
+struct Recorder {
+    events: Vector<ReplayEvent>,
+    pending: Vector<ReplayEvent>,
+    variant: Variant,
+}
+
+impl Recorder {
+    fn new(variant: Variant) -> Recorder {
+        Recorder {
+            events: Vector::new(),
+            pending: Vector::new(),
+            variant,
         }
+    }
+
+    fn to_json_lines(&self) -> String {
+        self.events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap() + "\n")
+            .collect()
+    }
+}
 
-        if let Some(prev) = identical_round {
-            println!("Identical cards for {}", prev);
-            let winner = players.head().unwrap();
-            println!("The winner of game {} is {}!", game, winner.player);
-            return winner.clone();
+impl Observer for Recorder {
+    fn game_started(&mut self, game: usize, decks: &Vector<Deck>) {
+        match self.pending.back_mut() {
+            Some(ReplayEvent::Round { sub_game, .. }) => *sub_game = Some(game),
+            _ => self.events.push_back(ReplayEvent::Start {
+                variant: self.variant,
+                decks: decks.clone(),
+            }),
         }
+    }
 
-        let updated_states = players.iter().fold(previous_states, |states, player| {
-            states.update_with(
-                player.player.to_owned(),
-                hashset!(player.cards.clone()),
-                |old, new| old.union(new),
-            )
+    fn round_started(&mut self, game: usize, round: usize, decks: &Vector<Deck>) {
+        self.pending.push_back(ReplayEvent::Round {
+            game,
+            round,
+            decks: decks.clone(),
+            played: Vector::new(),
+            sub_game: None,
+            winner: String::new(),
         });
+    }
 
-        let cards: Vector<_> = players
-            .iter_mut()
-            .filter_map(|deck| {
-                deck.next_card()
-                    .map(move |card| (deck.player.to_owned(), card))
-            })
-            .collect();
+    fn cards_played(&mut self, _game: usize, cards: &Vector<(String, usize)>) {
+        if let Some(ReplayEvent::Round { played, .. }) = self.pending.back_mut() {
+            *played = cards.clone();
+        }
+    }
 
-        for (name, card) in &cards {
-            println!("{} plays: {}", name, card)
+    fn repeated_state(&mut self, game: usize) {
+        self.events.push_back(ReplayEvent::Repeated { game });
+    }
+
+    fn round_won(&mut self, _game: usize, _round: usize, round_winner: &str) {
+        if let Some(mut round) = self.pending.pop_back() {
+            if let ReplayEvent::Round { winner, .. } = &mut round {
+                *winner = round_winner.to_owned();
+            }
+            self.events.push_back(round);
         }
+    }
 
-        let trigger_subgame = cards.iter().all(|(name, card)| {
-            players
-                .iter()
-                .find_map(|p| {
-                    if &p.player == name {
-                        Some(p.cards.len())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap()
-                >= *card
+    fn game_won(&mut self, game: usize, winner: &Deck) {
+        self.events.push_back(ReplayEvent::GameWon {
+            game,
+            winner: winner.player.clone(),
+            cards: winner.cards.clone(),
         });
+    }
+}
 
-        let winner = if trigger_subgame {
-            println!("Playing a sub-game to determine the winner...\n");
-            let sub_game_decks = players
-                .iter()
-                .map(|deck| {
-                    let current_card = cards
-                        .iter()
-                        .find_map(|(name, card)| {
-                            if name == &deck.player {
-                                Some(card)
-                            } else {
-                                None
-                            }
-                        })
-                        .unwrap();
-                    Deck {
-                        player: deck.player.to_owned(),
-                        cards: deck.cards.iter().take(*current_card).cloned().collect(),
-                    }
-                })
-                .collect();
-            let sub_game_winner = Deck::play_rec(1, game + 1, sub_game_decks, HashMap::new());
-            sub_game_winner.player
-        } else {
-            cards
-                .iter()
-                .max_by_key(|(_name, card)| card)
-                .map(|(name, _)| name)
-                .unwrap()
-                .to_owned()
-        };
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Replay {
+    events: Vector<ReplayEvent>,
+}
 
-        println!("{} wins round {} of game {}!", winner, round, game);
+impl Replay {
+    fn parse(log: &str) -> Result<Replay, ReplayError> {
+        let events = log
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| {
+                serde_json::from_str(line).map_err(|error| ReplayError {
+                    line: index + 1,
+                    message: error.to_string(),
+                })
+            })
+            .collect::<Result<Vector<ReplayEvent>, ReplayError>>()?;
+
+        match events.head() {
+            Some(ReplayEvent::Start { .. }) => Ok(Replay { events }),
+            _ => Err(ReplayError {
+                line: 1,
+                message: "a replay should open with a start event".to_owned(),
+            }),
+        }
+    }
 
-        let winning_deck = players
-            .iter_mut()
-            .find(|deck| deck.player == winner)
-            .unwrap();
-        let winners_cards: Vector<_> = cards
+    fn rounds(&self, game: usize) -> usize {
+        self.events
             .iter()
-            .sorted_by_key(|(name, _)| if name == &winner { 0 } else { 1 })
-            .map(|(_name, card)| card)
-            .cloned()
-            .collect();
+            .filter(|event| matches!(event, ReplayEvent::Round { game: g, .. } if *g == game))
+            .count()
+    }
 
-        winning_deck.add_cards(winners_cards);
+    fn winner(&self) -> Option<&str> {
+        self.events.iter().rev().find_map(|event| match event {
+            ReplayEvent::GameWon {
+                game: 1, winner, ..
+            } => Some(&winner[..]),
+            _ => None,
+        })
+    }
 
-        players.retain(|deck| !deck.is_empty());
-        if players.len() > 1 {
-            Deck::play_rec(round + 1, game, players, updated_states)
-        } else {
-            let overall_winner = players.head().unwrap();
-            println!(
-                "The winner of game {} is player {}!",
-                game, overall_winner.player
-            );
+    fn explain(&self, event: &ReplayEvent) -> String {
+        match event {
+            ReplayEvent::Start { variant, decks } => format!(
+                "{:?} with {}",
+                variant,
+                decks
+                    .iter()
+                    .map(|deck| format!("{} holding {} cards", deck.player, deck.cards.len()))
+                    .join(", ")
+            ),
+            ReplayEvent::Round {
+                game,
+                round,
+                played,
+                sub_game,
+                winner,
+                ..
+            } => format!(
+                "Game {} round {}: {} played, {} wins {}",
+                game,
+                round,
+                played
+                    .iter()
+                    .map(|(player, card)| format!("{} {}", player, card))
+                    .join(", "),
+                winner,
+                match sub_game {
+                    Some(sub_game) => format!("by winning game {}", sub_game),
+                    None => "with the highest card".to_owned(),
+                }
+            ),
+            ReplayEvent::Repeated { game } => format!(
+                "Game {} repeats an earlier table, the first seat wins",
+                game
+            ),
+            ReplayEvent::GameWon {
+                game,
+                winner,
+                cards,
+            } => format!(
+                "{} wins game {} after {} rounds holding {}",
+                winner,
+                game,
+                self.rounds(*game),
+                cards.iter().join(", ")
+            ),
+        }
+    }
+}
 
-            if game > 1 {
-                println!("\n...anyway, back to game {}", game - 1);
+struct Game {
+    variant: Variant,
+}
+
+impl Game {
+    fn new(variant: Variant) -> Game {
+        Game { variant }
+    }
+
+    // Sub-games are pushed on an explicit stack instead of recursing, so deep games can't overflow.
+    fn play(&self, decks: Vector<Deck>, mut observer: Option<&mut dyn Observer>) -> GameResult {
+        let mut stack = vec![Frame::new(1, decks)];
+        let mut total_rounds = 0;
+        let mut sub_games = 0;
+        let mut sub_game_winner: Option<String> = None;
+        let mut notify = |event: &mut dyn FnMut(&mut dyn Observer)| {
+            if let Some(observer) = observer.as_mut() {
+                event(*observer);
+            }
+        };
+        notify(&mut |o| o.game_started(1, &stack[0].decks));
+
+        loop {
+            let frame = stack.last_mut().unwrap();
+            let round_winner = match sub_game_winner.take() {
+                Some(winner) => Some(winner),
+                None if frame.decks.len() > 1 && frame.seen.insert(frame.state()).is_some() => {
+                    notify(&mut |o| o.repeated_state(frame.game));
+                    frame.decks.truncate(1);
+                    None
+                }
+                None if frame.decks.len() > 1 => {
+                    frame.round += 1;
+                    total_rounds += 1;
+                    notify(&mut |o| o.round_started(frame.game, frame.round, &frame.decks));
+
+                    frame.played = frame
+                        .decks
+                        .iter_mut()
+                        .filter_map(|deck| deck.next_card().map(|card| (deck.player.clone(), card)))
+                        .collect();
+                    notify(&mut |o| o.cards_played(frame.game, &frame.played));
+
+                    if self.variant == Variant::Recursive
+                        && frame
+                            .decks
+                            .iter()
+                            .zip(frame.played.iter())
+                            .all(|(deck, (_, card))| deck.cards.len() >= *card)
+                    {
+                        let sub_decks = frame
+                            .decks
+                            .iter()
+                            .zip(frame.played.iter())
+                            .map(|(deck, (_, card))| Deck {
+                                player: deck.player.clone(),
+                                cards: deck.cards.take(*card),
+                            })
+                            .collect();
+                        sub_games += 1;
+                        let game = sub_games + 1;
+                        let sub_game = Frame::new(game, sub_decks);
+                        notify(&mut |o| o.game_started(game, &sub_game.decks));
+                        stack.push(sub_game);
+                        continue;
+                    }
+
+                    // `max_by_key` keeps the last maximum, so reversing hands ties to the earliest seat.
+                    frame
+                        .played
+                        .iter()
+                        .rev()
+                        .max_by_key(|(_, card)| card)
+                        .map(|(name, _)| name.clone())
+                }
+                None => None,
+            };
+
+            if let Some(winner) = round_winner {
+                let won_cards: Vector<usize> = frame
+                    .played
+                    .iter()
+                    .sorted_by_key(|(name, card)| (name != &winner, cmp::Reverse(*card)))
+                    .map(|(_, card)| *card)
+                    .collect();
+                frame
+                    .decks
+                    .iter_mut()
+                    .find(|deck| deck.player == winner)
+                    .unwrap()
+                    .add_cards(won_cards);
+                notify(&mut |o| o.round_won(frame.game, frame.round, &winner));
+                frame.decks.retain(|deck| !deck.is_empty());
             }
 
-            overall_winner.clone()
+            if frame.decks.len() <= 1 {
+                let finished = stack.pop().unwrap();
+                let winner = finished
+                    .decks
+                    .head()
+                    .expect("A game needs at least one card");
+                notify(&mut |o| o.game_won(finished.game, winner));
+                if stack.is_empty() {
+                    return GameResult {
+                        winner: winner.player.clone(),
+                        cards: winner.cards.clone(),
+                        score: winner.score(),
+                        rounds: finished.round,
+                        total_rounds,
+                        sub_games,
+                    };
+                }
+                sub_game_winner = Some(winner.player.clone());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Deck {
+    player: String,
+    cards: Vector<usize>,
+}
+
+impl Deck {
+    fn parse_all(input: &Vector<String>) -> Vector<Deck> {
+        itertools::unfold(input.iter(), |iterator| {
+            let lines: Vector<_> = iterator
+                .by_ref()
+                .take_while(|line| !line.is_empty())
+                .collect();
+            if lines.is_empty() {
+                None
+            } else {
+                Some(Deck::parse(&lines))
+            }
+        })
+        .collect()
+    }
+
+    fn parse(input: &Vector<&String>) -> Deck {
+        let name = input.head().unwrap().replace(":", "");
+        let cards = input
+            .iter()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .map(|l| l.parse::<usize>().unwrap())
+            .collect();
+        Deck {
+            player: name,
+            cards,
         }
     }
 
@@ -280,3 +571,127 @@ impl fmt::Display for Deck {
         write!(f, "{}:\n{}", self.player, self.cards.iter().join("\n"))
     }
 }
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_combat() {
+        let decks = Deck::parse_all(&utils::read_strings("input/test.txt").unwrap());
+        let result = Game::new(Variant::Combat).play(decks, None);
+        assert_eq!(result.winner, "Player 2");
+        assert_eq!(result.score, 306);
+        assert_eq!(result.rounds, 29);
+        assert_eq!(result.sub_games, 0);
+    }
+
+    #[test]
+    fn test_recursive_combat() {
+        let decks = Deck::parse_all(&utils::read_strings("input/test.txt").unwrap());
+        let result = Game::new(Variant::Recursive).play(decks, None);
+        assert_eq!(result.winner, "Player 2");
+        assert_eq!(result.cards, im_rc::vector!(7, 5, 6, 2, 4, 1, 10, 8, 9, 3));
+        assert_eq!(result.score, 291);
+        assert_eq!(result.rounds, 17);
+        assert_eq!(result.sub_games, 4);
+    }
+
+    #[test]
+    fn test_repeated_state() {
+        struct Repeats(usize);
+        impl Observer for Repeats {
+            fn repeated_state(&mut self, _game: usize) {
+                self.0 += 1;
+            }
+        }
+
+        let input = "Player 1:\n43\n19\n\nPlayer 2:\n2\n29\n14"
+            .lines()
+            .map(String::from)
+            .collect();
+        let mut repeats = Repeats(0);
+        let result =
+            Game::new(Variant::Recursive).play(Deck::parse_all(&input), Some(&mut repeats));
+        assert_eq!(result.winner, "Player 1");
+        assert_eq!(repeats.0, 1);
+
+        let mut repeats = Repeats(0);
+        let result = Game::new(Variant::Combat).play(Deck::parse_all(&input), Some(&mut repeats));
+        assert_eq!(result.winner, "Player 1");
+        assert_eq!(result.cards, im_rc::vector!(43, 19));
+        assert_eq!(repeats.0, 1);
+    }
+
+    #[test]
+    fn test_three_players() {
+        let input = "Player 1:\n9\n2\n6\n\nPlayer 2:\n5\n8\n4\n\nPlayer 3:\n3\n7\n1\n10"
+            .lines()
+            .map(String::from)
+            .collect();
+        let decks = Deck::parse_all(&input);
+        for &variant in &[Variant::Combat, Variant::Recursive] {
+            let result = Game::new(variant).play(decks.clone(), None);
+            assert_eq!(
+                result.cards.iter().sorted().collect::<Vec<_>>(),
+                (1..=10).collect::<Vec<_>>().iter().collect::<Vec<_>>()
+            );
+        }
+
+        let combat = Game::new(Variant::Combat).play(decks.clone(), None);
+        assert_eq!(combat.winner, "Player 3");
+
+        let tie = "Player 1:\n5\n\nPlayer 2:\n5"
+            .lines()
+            .map(String::from)
+            .collect();
+        let result = Game::new(Variant::Combat).play(Deck::parse_all(&tie), None);
+        assert_eq!(result.winner, "Player 1");
+        assert_eq!(result.cards, im_rc::vector!(5, 5));
+    }
+
+    #[test]
+    fn test_replay() {
+        let decks = Deck::parse_all(&utils::read_strings("input/test.txt").unwrap());
+        let mut recorder = Recorder::new(Variant::Recursive);
+        let result = Game::new(Variant::Recursive).play(decks.clone(), Some(&mut recorder));
+
+        let log = recorder.to_json_lines();
+        assert_eq!(
+            log.lines().count(),
+            1 + result.total_rounds + result.sub_games + 1
+        );
+
+        let replay = Replay::parse(&log).unwrap();
+        assert_eq!(
+            replay.events[0],
+            ReplayEvent::Start {
+                variant: Variant::Recursive,
+                decks
+            }
+        );
+        assert_eq!(replay.rounds(1), result.rounds);
+        assert_eq!(replay.winner(), Some("Player 2"));
+        assert!(replay.events.iter().any(|event| matches!(
+            event,
+            ReplayEvent::Round {
+                sub_game: Some(2),
+                ..
+            }
+        )));
+
+        assert_eq!(
+            Replay::parse("{\"event\":\"repeated\",\"game\":1}\nnot json")
+                .unwrap_err()
+                .line,
+            2
+        );
+        assert_eq!(
+            Replay::parse("{\"event\":\"repeated\",\"game\":1}")
+                .unwrap_err()
+                .line,
+            1
+        );
+    }
+}
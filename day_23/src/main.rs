@@ -3,11 +3,13 @@ extern crate log;
 extern crate pretty_env_logger;
 
 use im_rc::vector;
-use im_rc::HashMap;
 use im_rc::Vector;
 use itertools::Itertools;
 use log::{debug, info};
 use pbr::ProgressBar;
+use std::cmp;
+use std::env;
+use std::fmt;
 
 fn main() {
     pretty_env_logger::init();
@@ -18,56 +20,155 @@ fn main() {
 
     info!("Solution to part one: {}", part_one(&input));
     info!("Solution to part two: {}", part_two(&input));
+
+    let args: Vector<String> = env::args().collect();
+    let param = |name: &str| args.iter().skip_while(|arg| *arg != name).nth(1);
+    if ["--labels", "--cups", "--moves", "--pick-up"]
+        .iter()
+        .any(|name| param(name).is_some())
+    {
+        let labels: Vector<u32> = param("--labels")
+            .map(|labels| {
+                labels
+                    .chars()
+                    .map(|c| c.to_digit(10).expect("Labels should be digits"))
+                    .collect()
+            })
+            .unwrap_or_else(|| input.clone());
+        let config = Config {
+            cups: param("--cups").map_or(labels.len() as u32, |cups| cups.parse().unwrap()),
+            moves: param("--moves").map_or(100, |moves| moves.parse().unwrap()),
+            pick_up: param("--pick-up").map_or(3, |pick_up| pick_up.parse().unwrap()),
+        };
+
+        let mut ring = play(&labels, &config).unwrap_or_else(|error| panic!("{}", error));
+        let after_one = ring.pick_up_after(1, cmp::min(config.cups as usize - 1, 20));
+        info!(
+            "{} cups, {} moves, picking up {}: {} follow cup 1, their product is {}",
+            config.cups,
+            config.moves,
+            config.pick_up,
+            after_one.iter().join(","),
+            after_one
+                .iter()
+                .take(2)
+                .map(|&cup| cup as u64)
+                .product::<u64>()
+        );
+    }
 }
 
 fn part_one(input: &Vector<u32>) -> String {
-    let mut progress = ProgressBar::new(100);
-    let ring = Ring::new(&input);
-    let largest = input.iter().max().unwrap();
-    let active = *input.head().unwrap();
+    let config = Config {
+        cups: input.len() as u32,
+        moves: 100,
+        pick_up: 3,
+    };
+    let mut ring = play(input, &config).unwrap_or_else(|error| panic!("{}", error));
+    ring.pick_up_after(1, input.len() - 1).iter().join("")
+}
 
-    let (_, mut ring) = (1..=100).fold((active, ring), |(active, ring), nb_move| {
-        progress.inc();
-        do_move(&nb_move, active, largest, ring)
-    });
+fn part_two(input: &Vector<u32>) -> u64 {
+    let config = Config {
+        cups: 1_000_000,
+        moves: 10_000_000,
+        pick_up: 3,
+    };
+    let ring = play(input, &config).unwrap_or_else(|error| panic!("{}", error));
 
-    progress.finish();
-    ring.pick_up_after(1, 8).iter().join("")
+    let a = ring.next(&1);
+    let b = ring.next(a);
+
+    *a as u64 * *b as u64
 }
 
-fn part_two(input: &Vector<u32>) -> u64 {
-    let mut progress = ProgressBar::new(10_000_000);
-    let largest = 1_000_000;
-    let mut padded_input: Vector<u32> = input.iter().copied().collect();
-    padded_input.append((10..=largest).collect());
-    let ring = Ring::new(&padded_input);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Config {
+    cups: u32,
+    moves: usize,
+    pick_up: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ConfigError {
+    NoLabels,
+    NotAPermutation(Vector<u32>),
+    TooFewCups { cups: u32, labels: usize },
+    PickUpTooLarge { pick_up: usize, cups: u32 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NoLabels => write!(f, "No cup labels given"),
+            ConfigError::NotAPermutation(labels) => write!(
+                f,
+                "Labels {} should number the cups 1 to {} exactly once",
+                labels.iter().join(","),
+                labels.len()
+            ),
+            ConfigError::TooFewCups { cups, labels } => {
+                write!(f, "{} cups can't hold {} labelled cups", cups, labels)
+            }
+            ConfigError::PickUpTooLarge { pick_up, cups } => write!(
+                f,
+                "Picking up {} cups leaves no destination among {} cups",
+                pick_up, cups
+            ),
+        }
+    }
+}
+
+// The labelled cups come first, the remaining cups up to `config.cups` follow in order.
+fn play(input: &Vector<u32>, config: &Config) -> Result<Ring, ConfigError> {
+    if input.is_empty() {
+        return Err(ConfigError::NoLabels);
+    }
+    if input.iter().sorted().copied().ne(1..=input.len() as u32) {
+        return Err(ConfigError::NotAPermutation(input.clone()));
+    }
+    if (config.cups as usize) < input.len() {
+        return Err(ConfigError::TooFewCups {
+            cups: config.cups,
+            labels: input.len(),
+        });
+    }
+    if config.pick_up + 1 >= config.cups as usize {
+        return Err(ConfigError::PickUpTooLarge {
+            pick_up: config.pick_up,
+            cups: config.cups,
+        });
+    }
+
+    let ring = Ring::padded(input, config.cups);
     let active = *input.head().unwrap();
+    let mut progress = ProgressBar::new(100);
+    let tick = cmp::max(config.moves / 100, 1);
 
-    let (_, ring) = (1..=10_000_000).fold((active, ring), |(active, ring), nb_move| {
-        progress.inc();
-        do_move(&nb_move, active, &largest, ring)
+    let (_, ring) = (1..=config.moves).fold((active, ring), |(active, ring), nb_move| {
+        if nb_move % tick == 0 {
+            progress.inc();
+        }
+        do_move(&nb_move, active, config, ring)
     });
 
     progress.finish();
-
-    let a = ring.next(&1);
-    let b = ring.next(a);
-
-    *a as u64 * *b as u64
+    Ok(ring)
 }
 
-fn do_move(move_nb: &usize, active: u32, largest: &u32, mut ring: Ring) -> (u32, Ring) {
+fn do_move(move_nb: &usize, active: u32, config: &Config, mut ring: Ring) -> (u32, Ring) {
     debug!("-- move {} --", move_nb);
-    debug!("cups: {:?}", ring.values());
-    let cups = ring.pick_up_after(active, 3);
+    debug!("cups: {:?}", ring.values_from(active));
+    let cups = ring.pick_up_after(active, config.pick_up);
     debug!("pick up: {:?}", cups);
 
-    let destination = (1..=4)
+    let largest = config.cups;
+    let destination = (1..=config.pick_up as u32 + 1)
         .map(|d| {
-            if (active as i64) - d <= 0 {
-                *largest - d as u32 + active
+            if d >= active {
+                largest - d + active
             } else {
-                active - d as u32
+                active - d
             }
         })
         .find(|dest| !cups.contains(dest))
@@ -79,75 +180,80 @@ fn do_move(move_nb: &usize, active: u32, largest: &u32, mut ring: Ring) -> (u32,
     (active, ring)
 }
 
+// Cups are labelled 1 to n, so the successor of every cup is stored at its label; index 0 is unused.
 struct Ring {
-    forward_links: HashMap<u32, u32>,
+    successors: Vec<u32>,
+    len: usize,
 }
 
 impl Ring {
     #[allow(dead_code)]
     fn len(&self) -> usize {
-        self.forward_links.len()
+        self.len
     }
 
-    fn values(&self) -> Vector<&u32> {
-        if let Some(start) = self.forward_links.keys().next() {
-            let mut result = vector!(start);
-            let mut next = self.next(start);
-            while next != start {
-                result.push_back(next);
-                next = self.next(next);
-            }
-            result
-        } else {
-            Vector::new()
+    fn values_from(&self, start: u32) -> Vector<u32> {
+        if self.len == 0 {
+            return Vector::new();
+        }
+        let mut result = vector!(start);
+        let mut next = *self.next(&start);
+        while next != start {
+            result.push_back(next);
+            next = *self.next(&next);
         }
+        result
     }
 
     fn next(&self, elem: &u32) -> &u32 {
-        self.forward_links.get(elem).unwrap()
+        &self.successors[*elem as usize]
     }
 
     fn pick_up_after(&mut self, start: u32, length: usize) -> Vector<u32> {
         let (end, slice) = (0..length).fold((start, vector!()), |(prev, mut slice), _| {
-            let next = self.forward_links.remove(&prev).unwrap();
+            let next = *self.next(&prev);
             slice.push_back(next);
             (next, slice)
         });
 
-        let tail = self.forward_links.remove(&end).unwrap();
-
-        self.forward_links.insert(start, tail);
+        self.successors[start as usize] = *self.next(&end);
+        self.len -= length;
         slice
     }
 
     fn insert_after(&mut self, start: u32, elements: Vector<u32>) {
-        let tail = self.forward_links.remove(&start).unwrap();
+        let tail = *self.next(&start);
         let last = elements.iter().fold(start, |prev, &next| {
-            self.forward_links.insert(prev, next);
+            if next as usize >= self.successors.len() {
+                self.successors.resize(next as usize + 1, 0);
+            }
+            self.successors[prev as usize] = next;
             next
         });
 
-        self.forward_links.insert(last, tail);
+        self.successors[last as usize] = tail;
+        self.len += elements.len();
     }
 
+    #[cfg(test)]
     fn new(inputs: &Vector<u32>) -> Ring {
-        match inputs.head() {
-            Some(&head) => {
-                let (mut links, last) = inputs.iter().skip(1).copied().fold(
-                    (HashMap::new(), head),
-                    |(mut links, last), elem| {
-                        links.insert(last, elem);
-                        (links, elem)
-                    },
-                );
-                links.insert(last, head);
-                Ring {
-                    forward_links: links,
-                }
-            }
-            None => Ring {
-                forward_links: HashMap::new(),
-            },
+        let size = inputs.iter().max().copied().unwrap_or(0);
+        Ring::padded(inputs, size)
+    }
+
+    // Follows `inputs` with the cups after the largest label up to `size`.
+    fn padded(inputs: &Vector<u32>, size: u32) -> Ring {
+        let largest = inputs.iter().max().copied().unwrap_or(0);
+        let order: Vec<u32> = inputs.iter().copied().chain(largest + 1..=size).collect();
+        let mut successors = vec![0; cmp::max(size, largest) as usize + 1];
+        order
+            .iter()
+            .zip(order.iter().cycle().skip(1))
+            .for_each(|(&cup, &next)| successors[cup as usize] = next);
+
+        Ring {
+            successors,
+            len: order.len(),
         }
     }
 }
@@ -183,4 +289,48 @@ mod test {
         let slice = ring.pick_up_after(1, 4);
         assert_eq!(slice, vector!(2, 5, 6, 3));
     }
+
+    #[test]
+    fn test_configurations() {
+        let input = vector!(3, 8, 9, 1, 2, 5, 4, 6, 7);
+        let config = Config {
+            cups: 9,
+            moves: 10,
+            pick_up: 3,
+        };
+        let mut ring = play(&input, &config).unwrap();
+        assert_eq!(ring.len(), 9);
+        assert_eq!(ring.pick_up_after(1, 8).iter().join(""), "92658374");
+        assert_eq!(part_one(&input), "67384529");
+
+        let mut ring = play(&input, &Config { cups: 20, ..config }).unwrap();
+        assert_eq!(ring.values_from(1).len(), 20);
+        assert_eq!(
+            ring.pick_up_after(1, 19)
+                .iter()
+                .sorted()
+                .copied()
+                .collect::<Vec<_>>(),
+            (2..=20).collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            play(
+                &input,
+                &Config {
+                    pick_up: 8,
+                    ..config
+                }
+            )
+            .err(),
+            Some(ConfigError::PickUpTooLarge {
+                pick_up: 8,
+                cups: 9
+            })
+        );
+        assert_eq!(
+            play(&vector!(1, 3), &config).err(),
+            Some(ConfigError::NotAPermutation(vector!(1, 3)))
+        );
+    }
 }
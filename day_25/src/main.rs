@@ -1,15 +1,20 @@
 extern crate log;
 extern crate pretty_env_logger;
 
-use itertools::Itertools;
-use log::{debug, info, trace};
+use im_rc::HashMap;
+use log::{debug, info};
+use std::env;
+use std::fmt;
+
+const MODULUS: u64 = 20201227;
+const SUBJECT_NUMBER: u64 = 7;
 
 fn main() {
     pretty_env_logger::init();
     info!("--- [AoC 2020] Day 25: Combo Breaker ---");
 
-    let card_public_key = 5764801;
-    let door_public_key = 17807724;
+    // let card_public_key = 5764801;
+    // let door_public_key = 17807724;
 
     let card_public_key = 12578151;
     let door_public_key = 5051300;
@@ -18,31 +23,315 @@ fn main() {
         "Solution to part one: {}",
         part_one(card_public_key, door_public_key)
     );
+
+    let args: Vec<String> = env::args().collect();
+    if let Some(position) = args.iter().position(|arg| arg == "--simulate") {
+        let loop_sizes: Option<Vec<u64>> = args[position + 1..]
+            .iter()
+            .take(2)
+            .map(|arg| arg.parse().ok())
+            .collect();
+        match loop_sizes.as_deref() {
+            Some(&[card, door]) => {
+                let protocol = Protocol::default();
+                let card = Device::new(DeviceKind::Card, &protocol, card);
+                let door = Device::new(DeviceKind::Door, &protocol, door);
+                match Handshake::perform(&protocol, &card, &door) {
+                    Ok(handshake) => println!("{}", handshake),
+                    Err(error) => println!("{}", error),
+                }
+            }
+            _ => println!("Usage: --simulate <card loop size> <door loop size>"),
+        }
+    }
 }
 
 fn part_one(card: u64, door: u64) -> u64 {
-    let result = (1..usize::MAX).try_fold(1, |value, loop_size| {
-        trace!("Loop size: {}", loop_size);
-        match (value * 7 as u64).rem_euclid(20201227) {
-            res if res == card => {
-                info!("Key found: {} at loop size {}", res, loop_size);
-                Err(transform(loop_size, door))
-            }
-            res if res == door => {
-                info!("Key found: {} at loop size {}", res, loop_size);
-                Err(transform(loop_size, card))
+    let protocol = Protocol::default();
+    let card_keys = protocol.crack(card).expect("Could not find encryption key");
+    info!("Card loop size: {}", card_keys.loop_size);
+    protocol.encryption_key(&card_keys, door)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Protocol {
+    modulus: u64,
+    subject_number: u64,
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol {
+            modulus: MODULUS,
+            subject_number: SUBJECT_NUMBER,
+        }
+    }
+}
+
+impl Protocol {
+    fn transform(&self, loop_size: u64, subject_number: u64) -> u64 {
+        debug!(
+            "Transforming {} with loop size {}",
+            subject_number, loop_size
+        );
+        mod_pow(subject_number, loop_size, self.modulus)
+    }
+
+    fn key_pair(&self, loop_size: u64) -> KeyPair {
+        KeyPair {
+            loop_size,
+            public_key: self.transform(loop_size, self.subject_number),
+        }
+    }
+
+    fn encryption_key(&self, own: &KeyPair, other_public_key: u64) -> u64 {
+        self.transform(own.loop_size, other_public_key)
+    }
+
+    // What an eavesdropper can do: rebuild a key pair from the public key alone.
+    fn crack(&self, public_key: u64) -> Option<KeyPair> {
+        discrete_log(self.modulus, self.subject_number, public_key).map(|loop_size| KeyPair {
+            loop_size,
+            public_key,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeyPair {
+    loop_size: u64,
+    public_key: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    Card,
+    Door,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Device {
+    kind: DeviceKind,
+    keys: KeyPair,
+}
+
+impl Device {
+    fn new(kind: DeviceKind, protocol: &Protocol, loop_size: u64) -> Device {
+        Device {
+            kind,
+            keys: protocol.key_pair(loop_size),
+        }
+    }
+
+    fn public_key(&self) -> u64 {
+        self.keys.public_key
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HandshakeError {
+    SameKind(DeviceKind),
+    KeyMismatch { card: u64, door: u64 },
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::SameKind(kind) => {
+                write!(
+                    f,
+                    "A handshake needs a card and a door, not two {:?}s",
+                    kind
+                )
             }
-            value => Ok(value),
+            HandshakeError::KeyMismatch { card, door } => write!(
+                f,
+                "Card derived encryption key {} but door derived {}",
+                card, door
+            ),
         }
-    });
+    }
+}
 
-    match result {
-        Err(encryption_key) => encryption_key,
-        Ok(_) => panic!("Could not find encryption key"),
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Handshake {
+    card: Device,
+    door: Device,
+    encryption_key: u64,
+}
+
+impl Handshake {
+    // Both devices only see each other's public key; the handshake holds when they agree on the key.
+    fn perform(protocol: &Protocol, a: &Device, b: &Device) -> Result<Handshake, HandshakeError> {
+        let (card, door) = match (a.kind, b.kind) {
+            (DeviceKind::Card, DeviceKind::Door) => (a, b),
+            (DeviceKind::Door, DeviceKind::Card) => (b, a),
+            (kind, _) => return Err(HandshakeError::SameKind(kind)),
+        };
+
+        let card_key = protocol.encryption_key(&card.keys, door.public_key());
+        let door_key = protocol.encryption_key(&door.keys, card.public_key());
+        if card_key != door_key {
+            return Err(HandshakeError::KeyMismatch {
+                card: card_key,
+                door: door_key,
+            });
+        }
+
+        Ok(Handshake {
+            card: *card,
+            door: *door,
+            encryption_key: card_key,
+        })
+    }
+}
+
+impl fmt::Display for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Card public key {} (loop size {}), door public key {} (loop size {}), encryption key {}",
+            self.card.public_key(),
+            self.card.keys.loop_size,
+            self.door.public_key(),
+            self.door.keys.loop_size,
+            self.encryption_key
+        )
+    }
+}
+
+fn mod_pow(base: u64, exponent: u64, modulus: u64) -> u64 {
+    let modulus = modulus as u128;
+    let (mut result, mut base, mut exponent) = (1 % modulus, base as u128 % modulus, exponent);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result as u64
+}
+
+// Extended Euclid; there is no inverse when value and modulus share a factor.
+fn mod_inverse(value: u64, modulus: u64) -> Option<u64> {
+    let (mut old_r, mut r) = (value as i128 % modulus as i128, modulus as i128);
+    let (mut old_s, mut s) = (1i128, 0i128);
+    while r != 0 {
+        let quotient = old_r / r;
+        (old_r, r) = (r, old_r - quotient * r);
+        (old_s, s) = (s, old_s - quotient * s);
+    }
+    if old_r == 1 {
+        Some(old_s.rem_euclid(modulus as i128) as u64)
+    } else {
+        None
+    }
+}
+
+// Baby-step giant-step: the smallest x with generator^x = target (mod modulus), in O(sqrt(modulus))
+// time and memory. Writing x = i * m + j, the baby steps remember generator^j for every j < m and the
+// giant steps walk target * generator^(-m * i) until one of them lands on a remembered value.
+fn discrete_log(modulus: u64, generator: u64, target: u64) -> Option<u64> {
+    if modulus == 1 {
+        return Some(0);
+    }
+    let target = target % modulus;
+    let steps = (modulus as f64).sqrt().ceil() as u64;
+
+    let mut baby_steps = HashMap::new();
+    let mut value = 1;
+    for j in 0..steps {
+        baby_steps.entry(value).or_insert(j);
+        value = (value as u128 * generator as u128 % modulus as u128) as u64;
     }
+
+    let giant_step = mod_inverse(mod_pow(generator, steps, modulus), modulus)?;
+    let mut value = target;
+    for i in 0..steps {
+        if let Some(j) = baby_steps.get(&value) {
+            return Some(i * steps + j);
+        }
+        value = (value as u128 * giant_step as u128 % modulus as u128) as u64;
+    }
+    None
 }
 
-fn transform(loop_size: usize, subject_number: u64) -> u64 {
-    debug!("Trying loop size: {}", loop_size);
-    (0..loop_size).fold(1, |value, _| (value * subject_number).rem_euclid(20201227))
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_discrete_log() {
+        assert_eq!(discrete_log(MODULUS, SUBJECT_NUMBER, 5764801), Some(8));
+        assert_eq!(discrete_log(MODULUS, SUBJECT_NUMBER, 17807724), Some(11));
+        let protocol = Protocol::default();
+        assert_eq!(protocol.transform(8, 17807724), 14897079);
+        assert_eq!(protocol.transform(11, 5764801), 14897079);
+
+        // 3 generates the multiplicative group modulo the prime 1_000_000_007.
+        let modulus = 1_000_000_007;
+        let target = mod_pow(3, 123_456_789, modulus);
+        assert_eq!(discrete_log(modulus, 3, target), Some(123_456_789));
+
+        // 4 only reaches the quadratic residues modulo 7.
+        assert_eq!(discrete_log(7, 4, 3), None);
+        assert_eq!(mod_inverse(6, 9), None);
+    }
+
+    #[test]
+    fn test_part_one() {
+        assert_eq!(part_one(5764801, 17807724), 14897079);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let protocol = Protocol::default();
+        let card = Device::new(DeviceKind::Card, &protocol, 8);
+        let door = Device::new(DeviceKind::Door, &protocol, 11);
+        assert_eq!((card.public_key(), door.public_key()), (5764801, 17807724));
+
+        let handshake = Handshake::perform(&protocol, &door, &card).unwrap();
+        assert_eq!(handshake.card, card);
+        assert_eq!(handshake.encryption_key, 14897079);
+        assert_eq!(protocol.crack(door.public_key()), Some(door.keys));
+
+        assert_eq!(
+            Handshake::perform(&protocol, &card, &card),
+            Err(HandshakeError::SameKind(DeviceKind::Card))
+        );
+    }
+
+    #[test]
+    fn test_random_handshakes() {
+        let mut generator = utils::XorShift::new(0x2545_f491_4f6c_dd1d);
+        let mut random = move |bound: u64| 1 + generator.next_u64() % (bound - 1);
+
+        let protocol = Protocol::default();
+        for _ in 0..50 {
+            let card = Device::new(DeviceKind::Card, &protocol, random(MODULUS));
+            let door = Device::new(DeviceKind::Door, &protocol, random(MODULUS));
+            let handshake = Handshake::perform(&protocol, &card, &door).unwrap();
+            assert_eq!(
+                part_one(card.public_key(), door.public_key()),
+                handshake.encryption_key
+            );
+        }
+
+        let other = Protocol {
+            modulus: 1_000_000_007,
+            subject_number: 5,
+        };
+        for _ in 0..10 {
+            let card = Device::new(DeviceKind::Card, &other, random(other.modulus));
+            let door = Device::new(DeviceKind::Door, &other, random(other.modulus));
+            let handshake = Handshake::perform(&other, &card, &door).unwrap();
+            let cracked = other.crack(card.public_key()).unwrap();
+            assert_eq!(
+                other.encryption_key(&cracked, door.public_key()),
+                handshake.encryption_key
+            );
+        }
+    }
 }
@@ -47,3 +47,23 @@ pub fn input_file_from_param() -> String {
         .nth(1)
         .expect("Pass the input file as first parameter")
 }
+
+// A xorshift generator for reproducible runs and tests without pulling in a random crate. Its
+// output is far from cryptographic. A zero state would only ever yield zeros, so the lowest bit of
+// the seed is always set.
+pub struct XorShift {
+    state: u64,
+}
+
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift { state: seed | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}